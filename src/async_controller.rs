@@ -0,0 +1,213 @@
+use crate::error::{ProtocolError, RetryAction, RetryPolicy};
+use crate::event::ControllerEvent;
+use crate::frame::FrameDecoder;
+use crate::model::ControllerModel;
+use crate::protocol::{
+    system_reply_data, Command, ControllerState, ControllerVersions, DeviceState, LinkStats, Protocol, ReplyMatcher,
+    Request, SystemStatus, Target, TestData, VexControllerBuilder,
+};
+use crate::transport::AsyncTransport;
+use async_stream::stream;
+use std::time::Duration;
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tokio_stream::Stream;
+use tracing::debug;
+
+impl VexControllerBuilder {
+    pub fn build_async(self, transport: Box<dyn AsyncTransport>) -> AsyncVexController {
+        AsyncVexController {
+            transport,
+            stats: LinkStats::default(),
+            settings: self,
+        }
+    }
+}
+
+/// Async version of [`VexController`](crate::protocol::VexController) for use
+/// on a tokio runtime. Waiting for replies and backing off between retries
+/// never blocks the thread.
+pub struct AsyncVexController {
+    transport: Box<dyn AsyncTransport>,
+    stats: LinkStats,
+    settings: VexControllerBuilder,
+}
+
+impl AsyncVexController {
+    pub fn new(transport: Box<dyn AsyncTransport>) -> Self {
+        VexControllerBuilder::new().build_async(transport)
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    pub fn model(&self) -> ControllerModel {
+        self.settings.model
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.settings.retry_policy
+    }
+
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.settings.retry_policy = policy;
+    }
+
+    pub async fn send_command(&mut self, cmd1: u8, cmd2: u8, data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        self.send_command_timeout(cmd1, cmd2, data, self.settings.timeout).await
+    }
+
+    pub async fn send_command_timeout(
+        &mut self,
+        cmd1: u8,
+        cmd2: u8,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let mut attempt = 0;
+        let mut backoff = self.settings.backoff;
+        loop {
+            attempt += 1;
+            match self.try_command(cmd1, cmd2, data, timeout).await {
+                Ok(payload) => return Ok(payload),
+                Err(e) if self.settings.retry_policy.decide(&e, attempt) == RetryAction::Retry => {
+                    debug!("Retrying {:02X} {:02X} in {:?} (attempt {}): {}", cmd1, cmd2, backoff, attempt, e);
+                    time::sleep(backoff).await;
+                    backoff = backoff.saturating_mul(2);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    async fn try_command(&mut self, cmd1: u8, cmd2: u8, data: &[u8], timeout: Duration) -> Result<Vec<u8>, ProtocolError> {
+        let command = Protocol::encode_command(cmd1, cmd2, data);
+
+        self.transport.clear_buffer()?;
+        self.transport.send_bytes(&command).await?;
+
+        let mut buffer = vec![0u8; self.settings.buffer_size];
+        let mut decoder = FrameDecoder::responses();
+        let mut matcher = ReplyMatcher::new(cmd1, cmd2);
+        let deadline = Instant::now() + timeout;
+
+        while Instant::now() < deadline {
+            let n = match time::timeout_at(deadline, self.transport.receive_bytes(&mut buffer)).await {
+                Ok(n) => n?,
+                Err(_) => break,
+            };
+            if n == 0 {
+                time::sleep(self.settings.read_delay).await;
+                continue;
+            }
+
+            decoder.push(&buffer[..n]);
+            if let Some(payload) = matcher.next_reply(&mut decoder, &mut self.stats) {
+                return Ok(payload);
+            }
+        }
+
+        Err(matcher.timed_out(&mut self.stats))
+    }
+
+    /// Send an extended system command and return the reply data after the ACK byte.
+    pub async fn send_system_command(&mut self, cmd: Command, data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let timeout = self.settings.timeout_for(cmd);
+        let payload = self.send_command_timeout(Command::SystemCdc as u8, cmd as u8, data, timeout).await?;
+        system_reply_data(cmd, &payload)
+    }
+
+    /// Send a request and decode its reply.
+    async fn request<T>(&mut self, request: Request<T>) -> Result<T, ProtocolError> {
+        let payload = match request.target {
+            Target::Controller(subcommand) => self.send_command(Command::ControllerCdc as u8, subcommand, &request.data).await?,
+            Target::System(cmd) => self.send_system_command(cmd, &request.data).await?,
+        };
+        (request.decode)(self.settings.model, &payload)
+    }
+
+    pub async fn get_state(&mut self) -> Result<ControllerState, ProtocolError> {
+        self.request(Request::get_state()).await
+    }
+
+    pub async fn get_versions(&mut self) -> Result<ControllerVersions, ProtocolError> {
+        self.request(Request::get_versions()).await
+    }
+
+    pub async fn get_pair_id(&mut self) -> Result<u8, ProtocolError> {
+        self.request(Request::get_pair_id()).await
+    }
+
+    pub async fn set_pair_id(&mut self, pair_id: u8) -> Result<(), ProtocolError> {
+        self.request(Request::set_pair_id(pair_id)).await
+    }
+
+    pub async fn start_calibration(&mut self) -> Result<(), ProtocolError> {
+        self.request(Request::start_calibration()).await
+    }
+
+    pub async fn abort_calibration(&mut self) -> Result<(), ProtocolError> {
+        self.request(Request::abort_calibration()).await
+    }
+
+    pub async fn get_device_state(&mut self) -> Result<DeviceState, ProtocolError> {
+        self.request(Request::get_device_state()).await
+    }
+
+    pub async fn set_test_mode(&mut self, enabled: bool) -> Result<(), ProtocolError> {
+        self.request(Request::set_test_mode(enabled)).await
+    }
+
+    pub async fn get_test_data(&mut self) -> Result<TestData, ProtocolError> {
+        self.request(Request::get_test_data()).await
+    }
+
+    pub async fn get_system_status(&mut self) -> Result<SystemStatus, ProtocolError> {
+        self.request(Request::get_system_status()).await
+    }
+
+    /// Play a rumble pattern on a V5 controller: `.` short, `-` long, ` ` pause.
+    pub async fn rumble(&mut self, pattern: &str) -> Result<(), ProtocolError> {
+        self.request(Request::rumble(&self.settings, pattern)?).await
+    }
+
+    /// Replace one line, 0 to 2, of the V5 controller screen.
+    pub async fn set_screen_line(&mut self, line: u8, text: &str) -> Result<(), ProtocolError> {
+        self.request(Request::set_screen_line(&self.settings, line, text)?).await
+    }
+
+    /// Send a factory ping and return the round-trip time.
+    pub async fn ping(&mut self) -> Result<Duration, ProtocolError> {
+        let start = Instant::now();
+        self.request(Request::ping()).await?;
+        Ok(start.elapsed())
+    }
+
+    /// Poll the state every `interval` and yield what changed between polls.
+    ///
+    /// The first state read is the baseline and produces no events. Failed
+    /// polls are yielded as errors and polling carries on; stop consuming the
+    /// stream to stop polling.
+    pub fn events(&mut self, interval: Duration) -> impl Stream<Item = Result<ControllerEvent, ProtocolError>> + '_ {
+        stream! {
+            let mut ticker = time::interval(interval);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+            let mut previous = None;
+
+            loop {
+                ticker.tick().await;
+                match self.get_state().await {
+                    Ok(state) => {
+                        if let Some(previous) = &previous {
+                            for event in ControllerEvent::between(previous, &state) {
+                                yield Ok(event);
+                            }
+                        }
+                        previous = Some(state);
+                    }
+                    Err(e) => yield Err(e),
+                }
+            }
+        }
+    }
+}
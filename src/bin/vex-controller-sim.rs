@@ -0,0 +1,176 @@
+use anyhow::Result;
+use clap::Parser;
+use std::path::PathBuf;
+use vex_controller::model::ControllerModel;
+
+#[derive(Parser, Debug)]
+#[command(author, version, about = "Virtual VEX IQ Gen 2 or V5 controller on a pseudo-terminal", long_about = None)]
+struct Args {
+    /// Scenario file describing controller input over time
+    #[arg(short, long)]
+    scenario: Option<PathBuf>,
+
+    /// Create a symlink to the pseudo-terminal at this path
+    #[arg(long)]
+    link: Option<PathBuf>,
+
+    /// Pair ID reported by the simulated controller
+    #[arg(long, default_value_t = 0)]
+    pair_id: u8,
+
+    /// Controller model to simulate: iq2 or v5
+    #[arg(long, default_value = "iq2")]
+    model: ControllerModel,
+
+    /// Exit this many milliseconds after the last scenario step (runs forever if not set)
+    #[arg(long)]
+    linger: Option<u64>,
+}
+
+#[cfg(target_os = "linux")]
+mod pty {
+    use anyhow::{bail, Context, Result};
+    use std::fs::{self, File};
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd};
+    use std::path::{Path, PathBuf};
+    use std::ptr;
+    use std::time::Duration;
+
+    /// A pseudo-terminal pair. The slave side stays open so the master never
+    /// sees a hangup while the host reopens the port.
+    pub struct Pty {
+        pub master: File,
+        _slave: File,
+        path: PathBuf,
+    }
+
+    impl Pty {
+        pub fn open() -> Result<Self> {
+            let mut master = 0;
+            let mut slave = 0;
+            let rc = unsafe { libc::openpty(&mut master, &mut slave, ptr::null_mut(), ptr::null(), ptr::null()) };
+            if rc != 0 {
+                return Err(io::Error::last_os_error()).context("Failed to open pseudo-terminal");
+            }
+            let (master, slave) = unsafe { (File::from_raw_fd(master), File::from_raw_fd(slave)) };
+
+            // Raw mode so the line discipline passes binary frames through untouched
+            unsafe {
+                let mut termios: libc::termios = std::mem::zeroed();
+                if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                    return Err(io::Error::last_os_error()).context("Failed to read terminal attributes");
+                }
+                libc::cfmakeraw(&mut termios);
+                if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                    return Err(io::Error::last_os_error()).context("Failed to set raw mode");
+                }
+            }
+
+            let path = fs::read_link(format!("/proc/self/fd/{}", slave.as_raw_fd()))
+                .context("Failed to resolve pseudo-terminal path")?;
+
+            Ok(Self { master, _slave: slave, path })
+        }
+
+        pub fn path(&self) -> &Path {
+            &self.path
+        }
+
+        /// Wait until the host has written something, or the timeout expires.
+        pub fn wait_readable(&self, timeout: Duration) -> Result<bool> {
+            let mut fds = libc::pollfd {
+                fd: self.master.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let rc = unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) };
+            if rc < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    return Ok(false);
+                }
+                return Err(err.into());
+            }
+            if fds.revents & (libc::POLLERR | libc::POLLNVAL) != 0 {
+                bail!("Pseudo-terminal closed");
+            }
+            Ok(fds.revents & libc::POLLIN != 0)
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn main() -> Result<()> {
+    use std::io::{Read, Write};
+    use std::time::{Duration, Instant};
+    use tracing::info;
+    use vex_controller::simulator::{Scenario, SimulatedController};
+    use vex_controller::transport::Transport;
+
+    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+
+    let mut sim = SimulatedController::with_model(args.model);
+    let handle = sim.handle();
+    handle.set_pair_id(args.pair_id);
+
+    let scenario = match &args.scenario {
+        Some(path) => {
+            let scenario = Scenario::load(path)?;
+            handle.load_scenario(&scenario);
+            info!("Loaded scenario {} ({} ms)", path.display(), scenario.duration().as_millis());
+            Some(scenario)
+        }
+        None => None,
+    };
+
+    let mut pty = pty::Pty::open()?;
+    if let Some(link) = &args.link {
+        let _ = std::fs::remove_file(link);
+        std::os::unix::fs::symlink(pty.path(), link)?;
+        info!("Linked {} -> {}", link.display(), pty.path().display());
+    }
+
+    info!("Simulated controller listening on {}", pty.path().display());
+    println!("{}", pty.path().display());
+
+    let mut buffer = [0u8; 1024];
+    let mut finished_at: Option<Instant> = None;
+
+    loop {
+        if pty.wait_readable(Duration::from_millis(10))? {
+            let n = pty.master.read(&mut buffer)?;
+            sim.send_bytes(&buffer[..n])?;
+        }
+
+        let n = sim.receive_bytes(&mut buffer)?;
+        if n > 0 {
+            pty.master.write_all(&buffer[..n])?;
+        }
+
+        if let (Some(linger), Some(_)) = (args.linger, &scenario) {
+            // Applies due steps; the script clock only runs once the host has started talking
+            handle.state();
+            if handle.pending_steps() == 0 {
+                let finished = *finished_at.get_or_insert_with(Instant::now);
+                if finished.elapsed() >= Duration::from_millis(linger) {
+                    info!("Scenario finished");
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some(link) = &args.link {
+        let _ = std::fs::remove_file(link);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn main() -> Result<()> {
+    let _ = Args::parse();
+    anyhow::bail!("vex-controller-sim requires Linux pseudo-terminals")
+}
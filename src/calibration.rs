@@ -0,0 +1,56 @@
+use crate::protocol::{ControllerState, VexController};
+use anyhow::{bail, Result};
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// How often the state is read while calibrating
+pub const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// How long the controller has to report calibration as active after `StartJsCal`
+const START_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How a calibration run ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CalibrationOutcome {
+    /// Both sticks were calibrated and the result was confirmed on the controller
+    Complete,
+    /// Calibration stopped before both sticks were done, for example after
+    /// `AbortJsCal`
+    Aborted,
+}
+
+/// Start joystick calibration and follow it until the controller ends it.
+///
+/// `progress` is called with every state read, so callers can show which
+/// sticks are done. Once both are, the user confirms with E Up on the
+/// controller, which ends calibration.
+pub fn run_calibration(
+    controller: &mut VexController,
+    mut progress: impl FnMut(&ControllerState),
+) -> Result<CalibrationOutcome> {
+    match controller.start_calibration() {
+        Ok(_) => info!("Calibration command sent"),
+        Err(e) => warn!("Calibration command warning: {}", e),
+    }
+
+    let start = Instant::now();
+    let mut started = false;
+    let mut sticks_done = false;
+
+    loop {
+        let state = controller.get_state()?;
+        progress(&state);
+
+        if state.cal_active {
+            started = true;
+            sticks_done = state.cal_left && state.cal_right;
+        } else if started {
+            // The sticks read as not done once calibration is over, so go by the last active state
+            return Ok(if sticks_done { CalibrationOutcome::Complete } else { CalibrationOutcome::Aborted });
+        } else if start.elapsed() > START_TIMEOUT {
+            bail!("Controller did not start calibrating");
+        }
+
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
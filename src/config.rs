@@ -0,0 +1,267 @@
+use crate::model::DeviceId;
+use crate::protocol::{
+    Axis, Button, ControllerState, Version, VexControllerBuilder, DEFAULT_BACKOFF, DEFAULT_BUFFER_SIZE, DEFAULT_READ_DELAY, DEFAULT_TIMEOUT,
+    DEFAULT_TRANSFER_TIMEOUT,
+};
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Settings read from `config.toml`. Every field is optional.
+///
+/// ```toml
+/// port = "/dev/ttyACM0"
+/// serial = "0123ABCD"
+/// min_version = "1.0.4"
+///
+/// [link]
+/// timeout_ms = 500
+/// transfer_timeout_ms = 20000
+/// retries = 2
+/// backoff_ms = 50
+/// read_delay_ms = 5
+/// buffer_size = 1024
+///
+/// [[device]]
+/// serial = "0123ABCD"
+/// pair_id = 2
+/// player = 2
+/// mapping = { e_up = "e_down", e_down = "e_up" }
+/// calibration = { deadzone = 6, centre = { left_x = 131 } }
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Serial port to use instead of auto-detecting one
+    pub port: Option<String>,
+    /// USB serial number of the controller to use instead of auto-detecting one
+    pub serial: Option<String>,
+    /// Oldest main firmware `info` accepts without a warning
+    pub min_version: Option<Version>,
+    pub link: LinkConfig,
+    #[serde(rename = "device")]
+    pub devices: Vec<DeviceConfig>,
+}
+
+/// Settings for one controller, picked by its [`DeviceId`].
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceConfig {
+    /// USB serial number
+    pub serial: String,
+    /// Only apply while the controller has this pair ID
+    #[serde(default)]
+    pub pair_id: Option<u8>,
+    /// Player number from 1, added to the virtual gamepad's name. No two
+    /// controllers may share one.
+    #[serde(default)]
+    pub player: Option<u8>,
+    /// Buttons to report as other buttons. Unlisted buttons stay as they are.
+    #[serde(default)]
+    pub mapping: HashMap<Button, Button>,
+    #[serde(default)]
+    pub calibration: Calibration,
+}
+
+impl DeviceConfig {
+    /// Remap buttons and correct the sticks.
+    pub fn apply(&self, state: &mut ControllerState) {
+        if !self.mapping.is_empty() {
+            state.buttons = state.buttons.iter().map(|button| *self.mapping.get(&button).unwrap_or(&button)).collect();
+        }
+        for axis in Axis::ALL {
+            state.set_axis(axis, self.calibration.correct(axis, state.axis(axis)));
+        }
+    }
+}
+
+/// Stick corrections for a controller whose sticks don't rest at 127.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Calibration {
+    /// Readings this close to the centre are reported as centred
+    pub deadzone: u8,
+    /// Resting reading of each axis that doesn't rest at 127
+    pub centre: AxisCentres,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AxisCentres {
+    pub left_x: Option<u8>,
+    pub left_y: Option<u8>,
+    pub right_x: Option<u8>,
+    pub right_y: Option<u8>,
+}
+
+impl Calibration {
+    /// Move the axis' resting reading to 127, keeping both ends where they
+    /// are, then apply the deadzone.
+    pub fn correct(&self, axis: Axis, raw: u8) -> u8 {
+        let centre = match axis {
+            Axis::LeftX => self.centre.left_x,
+            Axis::LeftY => self.centre.left_y,
+            Axis::RightX => self.centre.right_x,
+            Axis::RightY => self.centre.right_y,
+        };
+        let value = match centre {
+            Some(centre) if centre > 0 && centre < u8::MAX => {
+                let (raw, centre) = (raw as u32, centre as u32);
+                let low = Axis::CENTRE as u32;
+                let high = (u8::MAX - Axis::CENTRE) as u32;
+                if raw < centre {
+                    raw * low / centre
+                } else {
+                    low + (raw - centre) * high / (u8::MAX as u32 - centre)
+                }
+            }
+            _ => raw as u32,
+        } as u8;
+
+        if value.abs_diff(Axis::CENTRE) <= self.deadzone {
+            Axis::CENTRE
+        } else {
+            value
+        }
+    }
+}
+
+/// Timing and retry settings for the serial link.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LinkConfig {
+    pub timeout_ms: u64,
+    pub transfer_timeout_ms: u64,
+    pub retries: u32,
+    pub backoff_ms: u64,
+    pub read_delay_ms: u64,
+    pub buffer_size: usize,
+}
+
+impl Default for LinkConfig {
+    fn default() -> Self {
+        Self {
+            timeout_ms: DEFAULT_TIMEOUT.as_millis() as u64,
+            transfer_timeout_ms: DEFAULT_TRANSFER_TIMEOUT.as_millis() as u64,
+            retries: 0,
+            backoff_ms: DEFAULT_BACKOFF.as_millis() as u64,
+            read_delay_ms: DEFAULT_READ_DELAY.as_millis() as u64,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+        }
+    }
+}
+
+impl LinkConfig {
+    pub fn builder(&self) -> VexControllerBuilder {
+        VexControllerBuilder::new()
+            .timeout(Duration::from_millis(self.timeout_ms))
+            .transfer_timeout(Duration::from_millis(self.transfer_timeout_ms))
+            .retries(self.retries)
+            .backoff(Duration::from_millis(self.backoff_ms))
+            .read_delay(Duration::from_millis(self.read_delay_ms))
+            .buffer_size(self.buffer_size)
+    }
+}
+
+impl Config {
+    /// Settings for this controller. An entry with a matching pair ID wins
+    /// over one that doesn't give a pair ID.
+    pub fn device(&self, id: &DeviceId) -> Option<&DeviceConfig> {
+        let for_serial = || self.devices.iter().filter(|device| device.serial == id.serial_number);
+        for_serial()
+            .find(|device| device.pair_id == Some(id.pair_id))
+            .or_else(|| for_serial().find(|device| device.pair_id.is_none()))
+    }
+
+    /// Settings for the controller with this serial number that don't depend
+    /// on its pair ID, for when the pair ID can't be read yet.
+    pub fn device_without_pair_id(&self, serial: &str) -> Option<&DeviceConfig> {
+        self.devices.iter().find(|device| device.serial == serial && device.pair_id.is_none())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        Self::parse(&text).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    /// Read a config from TOML text, checking the `[[device]]` player numbers.
+    pub fn parse(text: &str) -> Result<Self> {
+        let config: Self = toml::from_str(text)?;
+        config.check_players()?;
+        Ok(config)
+    }
+
+    /// Player numbers start at 1 and each belongs to one controller. Entries
+    /// for the same serial number, one per pair ID, may share a number since
+    /// only one of them applies at a time.
+    fn check_players(&self) -> Result<()> {
+        let mut players: HashMap<u8, &str> = HashMap::new();
+        for device in &self.devices {
+            let Some(player) = device.player else { continue };
+            if player == 0 {
+                bail!("Device {} has player 0, players start at 1", device.serial);
+            }
+            match players.insert(player, &device.serial) {
+                Some(other) if other != device.serial => {
+                    bail!("Devices {} and {} both have player {}", other, device.serial, player)
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Load the config from [`Config::default_path`], or use defaults if there is none.
+    pub fn load_default() -> Result<Self> {
+        match Self::default_path() {
+            Some(path) if path.exists() => Self::load(path),
+            _ => Ok(Self::default()),
+        }
+    }
+
+    /// `vex-controller/config.toml` in the user's config directory.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = if cfg!(target_os = "windows") {
+            env::var_os("APPDATA").map(PathBuf::from)
+        } else {
+            env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        };
+        base.map(|dir| dir.join("vex-controller").join("config.toml"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn link_settings_reach_the_builder() {
+        let text = "[link]\ntimeout_ms = 120\ntransfer_timeout_ms = 4500\nretries = 3\nbackoff_ms = 7\nread_delay_ms = 2\nbuffer_size = 64";
+        let builder = Config::parse(text).unwrap().link.builder();
+
+        assert_eq!(builder.timeout, Duration::from_millis(120));
+        assert_eq!(builder.transfer_timeout, Duration::from_millis(4500));
+        assert_eq!(builder.retry_policy.max_retries(), 3);
+        assert_eq!(builder.backoff, Duration::from_millis(7));
+        assert_eq!(builder.read_delay, Duration::from_millis(2));
+        assert_eq!(builder.buffer_size, 64);
+    }
+
+    #[test]
+    fn missing_link_settings_keep_the_defaults() {
+        let builder = Config::parse("[link]\nretries = 1").unwrap().link.builder();
+
+        assert_eq!(builder.timeout, DEFAULT_TIMEOUT);
+        assert_eq!(builder.transfer_timeout, DEFAULT_TRANSFER_TIMEOUT);
+        assert_eq!(builder.backoff, DEFAULT_BACKOFF);
+        assert_eq!(builder.read_delay, DEFAULT_READ_DELAY);
+        assert_eq!(builder.buffer_size, DEFAULT_BUFFER_SIZE);
+    }
+}
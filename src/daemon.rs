@@ -0,0 +1,596 @@
+use crate::async_controller::AsyncVexController;
+use crate::config::DeviceConfig;
+use crate::device_monitor::DeviceMonitor;
+use crate::error::{ErrorKind, RetryAction};
+use crate::gamepad::GamepadHandler;
+use crate::model::ControllerModel;
+use crate::protocol::{ControllerState, DeviceState, VexControllerBuilder};
+use crate::serial::{self, PortSelector};
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncWriteExt, Stdout};
+use tokio::net::UdpSocket;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+use tokio::task::{self, JoinHandle, JoinSet};
+use tokio::time::{self, Instant, MissedTickBehavior};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+
+/// How often the poller reads the controller
+pub const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// States each sink can fall behind by before it starts skipping them
+pub const CHANNEL_CAPACITY: usize = 16;
+
+/// How often the device state is re-read to report link and charging changes
+const DEVICE_STATE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Consecutive failures after which a sink is stopped
+const MAX_SINK_FAILURES: u32 = 50;
+
+/// Requests that can wait for the poller before new ones are dropped
+const REQUEST_CAPACITY: usize = 4;
+
+/// Polls in a row that can time out before the controller is taken to be gone
+const MAX_POLL_TIMEOUTS: u32 = 5;
+
+/// Longest wait for a device change between reconnect attempts. Ports that
+/// never show up as device changes, like the simulator's, are retried this often.
+const RECONNECT_WAIT: Duration = Duration::from_secs(2);
+
+/// A command for the controller, sent by the poller between polls.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControllerRequest {
+    /// Play a rumble pattern, see [`VexController::rumble`](crate::protocol::VexController::rumble)
+    Rumble(String),
+}
+
+/// What the poller publishes to sinks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DaemonEvent {
+    /// A state read from the controller
+    State(ControllerState),
+    /// The controller is gone. No states follow until it is back.
+    Disconnected,
+}
+
+/// Consumes the controller states published by the poller.
+///
+/// Every sink runs in its own task with its own queue, so a slow or failing
+/// sink only ever delays itself.
+#[async_trait]
+pub trait StateSink: Send {
+    fn name(&self) -> &str;
+
+    async fn handle(&mut self, state: &ControllerState) -> Result<()>;
+
+    /// The controller is gone until the next [`StateSink::handle`]. Does
+    /// nothing by default.
+    async fn disconnected(&mut self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Skip straight to the newest state when several are queued. Right for
+    /// sinks that mirror the current state rather than record every one.
+    fn latest_only(&self) -> bool {
+        false
+    }
+}
+
+/// Polls one controller, fans its state out to sinks and passes their
+/// requests back to the controller. Sinks keep running while the controller
+/// is reconnected, and are told when it goes away.
+pub struct Daemon {
+    sender: broadcast::Sender<DaemonEvent>,
+    sinks: JoinSet<()>,
+    request_sender: mpsc::Sender<ControllerRequest>,
+    requests: mpsc::Receiver<ControllerRequest>,
+}
+
+impl Daemon {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        let (request_sender, requests) = mpsc::channel(REQUEST_CAPACITY);
+        Self { sender, sinks: JoinSet::new(), request_sender, requests }
+    }
+
+    /// Receive everything published, for consumers that aren't a [`StateSink`].
+    pub fn subscribe(&self) -> broadcast::Receiver<DaemonEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Send requests to the controller. They are carried out after the next poll.
+    pub fn requests(&self) -> mpsc::Sender<ControllerRequest> {
+        self.request_sender.clone()
+    }
+
+    /// Start a sink in its own task. Must be called inside a tokio runtime.
+    pub fn add_sink(&mut self, sink: impl StateSink + 'static) {
+        let receiver = self.sender.subscribe();
+        self.sinks.spawn(run_sink(Box::new(sink), receiver));
+    }
+
+    /// Poll the controller until every sink has stopped. `connect` opens the
+    /// controller, and is called again whenever the retry policy asks for a
+    /// reconnect or polls keep timing out. A controller that isn't there yet
+    /// is waited for.
+    pub async fn run(mut self, mut connect: impl FnMut() -> Result<AsyncVexController>) -> Result<()> {
+        let mut controller = wait_for_controller(&mut connect).await;
+        let mut ticker = time::interval(POLL_INTERVAL);
+        ticker.set_missed_tick_behavior(MissedTickBehavior::Skip);
+        let mut device_state: Option<DeviceState> = None;
+        let mut next_device_poll = Instant::now();
+        let mut timeouts = 0;
+        // Set from the first failed poll until a state comes back, so a run of them warns once
+        let mut failing = false;
+
+        loop {
+            ticker.tick().await;
+
+            while let Some(result) = self.sinks.try_join_next() {
+                if let Err(e) = result {
+                    error!("Sink task failed: {}", e);
+                }
+            }
+            if self.sinks.is_empty() && self.sender.receiver_count() == 0 {
+                bail!("All sinks have stopped");
+            }
+
+            if Instant::now() >= next_device_poll {
+                next_device_poll = Instant::now() + DEVICE_STATE_INTERVAL;
+                if let Ok(state) = controller.get_device_state().await {
+                    if device_state != Some(state) {
+                        info!("Controller {}", state);
+                        device_state = Some(state);
+                    }
+                }
+            }
+
+            match controller.get_state().await {
+                Ok(state) => {
+                    timeouts = 0;
+                    failing = false;
+                    // Only fails when nobody is subscribed, which is checked above
+                    let _ = self.sender.send(DaemonEvent::State(state));
+                }
+                Err(e) => {
+                    // Only timeouts in a row mean the controller is gone
+                    if e.kind() == ErrorKind::Timeout {
+                        timeouts += 1;
+                    } else {
+                        timeouts = 0;
+                    }
+                    let action = if timeouts >= MAX_POLL_TIMEOUTS {
+                        RetryAction::Reconnect
+                    } else {
+                        controller.retry_policy().action_for(e.kind())
+                    };
+                    match action {
+                        // The next poll is the retry
+                        RetryAction::Retry => debug!("Failed to get state, polling again: {}", e),
+                        RetryAction::Reconnect => {
+                            error!("Lost connection to controller: {}", e);
+                            // Sinks outlive the connection, so the gamepad stays
+                            // plugged in and idle until the controller is back
+                            let _ = self.sender.send(DaemonEvent::Disconnected);
+                            // Closing the port lets the device come back under the same name
+                            drop(controller);
+                            controller = wait_for_controller(&mut connect).await;
+                            device_state = None;
+                            timeouts = 0;
+                            failing = false;
+                            // Rumble from while the controller was away is stale
+                            while self.requests.try_recv().is_ok() {}
+                        }
+                        RetryAction::Fail if failing => debug!("Still failing to get state: {}", e),
+                        RetryAction::Fail => {
+                            warn!("Failed to get state: {}", e);
+                            failing = true;
+                        }
+                    }
+                }
+            }
+
+            while let Ok(request) = self.requests.try_recv() {
+                let result = match &request {
+                    ControllerRequest::Rumble(pattern) => controller.rumble(pattern).await,
+                };
+                if let Err(e) = result {
+                    warn!("Failed to send {:?}: {}", request, e);
+                }
+            }
+        }
+    }
+}
+
+impl Default for Daemon {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One controller for the daemon to drive.
+pub struct DaemonTarget {
+    /// Finds the controller again after it was unplugged, even under another port name
+    pub selector: PortSelector,
+    /// Model to assume for ports that don't report one, like the simulator's
+    pub model: ControllerModel,
+    pub settings: Option<DeviceConfig>,
+    /// Added to the gamepad name, and picks the UDP port when several
+    /// controllers are driven
+    pub player: Option<u8>,
+}
+
+/// Give every target without a configured player the lowest free player
+/// number, in port order, so the same controllers always get the same numbers.
+pub fn assign_players(targets: &mut [DaemonTarget]) {
+    let mut taken: Vec<u8> = targets.iter().filter_map(|target| target.player).collect();
+    let mut next = 1;
+    for target in targets.iter_mut().filter(|target| target.player.is_none()) {
+        while taken.contains(&next) {
+            next += 1;
+        }
+        target.player = Some(next);
+        taken.push(next);
+    }
+}
+
+/// Drive every target at once, each on its own task, until they have all
+/// stopped. A single target runs directly, with the status line unless quiet.
+/// With several, `udp` is the port for player 1 and each further player
+/// gets the next one up.
+pub async fn run_targets(targets: Vec<DaemonTarget>, builder: VexControllerBuilder, udp: Option<SocketAddr>, quiet: bool) -> Result<()> {
+    if targets.len() == 1 {
+        let target = targets.into_iter().next().expect("one target");
+        return run_controller(target, builder, udp, !quiet).await;
+    }
+
+    // Several status lines would overwrite each other
+    info!("Driving {} controllers", targets.len());
+    let mut tasks = JoinSet::new();
+    for target in targets {
+        let player = target.player.unwrap_or_default();
+        let span = info_span!("controller", player);
+        let udp = udp.map(|addr| SocketAddr::new(addr.ip(), addr.port().saturating_add(player.saturating_sub(1) as u16)));
+        tasks.spawn(run_controller(target, builder.clone(), udp, false).instrument(span));
+    }
+    while let Some(result) = tasks.join_next().await {
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => error!("Controller stopped: {:#}", e),
+            Err(e) => error!("Controller task failed: {}", e),
+        }
+    }
+    bail!("Every controller has stopped")
+}
+
+/// Run a [`Daemon`] for one controller, with a virtual gamepad and the
+/// console and UDP sinks asked for.
+async fn run_controller(target: DaemonTarget, builder: VexControllerBuilder, udp: Option<SocketAddr>, console: bool) -> Result<()> {
+    let DaemonTarget { selector, model, settings, player } = target;
+    let mut daemon = Daemon::new();
+
+    // Rumble uses an experimental command, so games are only offered force feedback when it can be sent
+    let sink = GamepadSink::new(player, builder.is_experimental()).with_requests(daemon.requests());
+    daemon.add_sink(match settings {
+        Some(settings) => sink.with_settings(settings),
+        None => sink,
+    });
+    if console {
+        daemon.add_sink(ConsoleSink::new());
+    }
+    if let Some(addr) = udp {
+        daemon.add_sink(UdpSink::new(addr).await?);
+        info!("Sending controller state to {}", addr);
+    }
+
+    info!("Listening for controller data...");
+    daemon
+        .run(|| {
+            // Whatever comes back on the port decides the model, not what was there before
+            let found = serial::find_controller(&selector)?;
+            let model = found.model.unwrap_or(model);
+            let transport = serial::AsyncSerialTransport::new(Some(found.name))?;
+            Ok(builder.clone().model(model).build_async(Box::new(transport)))
+        })
+        .await
+}
+
+/// Keep trying to open the controller until it is there, waiting for a
+/// device to be plugged in or removed between attempts.
+async fn wait_for_controller(connect: &mut impl FnMut() -> Result<AsyncVexController>) -> AsyncVexController {
+    // Watching from before the first attempt means a controller that shows up
+    // right after an attempt fails still wakes the next wait
+    let monitor = Arc::new(DeviceMonitor::new());
+    let mut waited = false;
+    loop {
+        match connect() {
+            Ok(controller) => {
+                if waited {
+                    info!("Controller is back");
+                }
+                return controller;
+            }
+            Err(e) if !waited => warn!("Failed to open controller, waiting for it: {:#}", e),
+            Err(e) => debug!("Controller not back yet: {:#}", e),
+        }
+        waited = true;
+
+        let monitor = monitor.clone();
+        if let Ok(true) = task::spawn_blocking(move || monitor.wait(RECONNECT_WAIT)).await {
+            debug!("A device changed, trying the controller again");
+        }
+    }
+}
+
+async fn run_sink(mut sink: Box<dyn StateSink>, mut receiver: broadcast::Receiver<DaemonEvent>) {
+    let mut failures = 0;
+    loop {
+        let mut event = match receiver.recv().await {
+            Ok(event) => event,
+            Err(RecvError::Lagged(skipped)) => {
+                debug!("{} sink fell behind, skipped {} states", sink.name(), skipped);
+                continue;
+            }
+            Err(RecvError::Closed) => return,
+        };
+        if sink.latest_only() {
+            while let Ok(newer) = receiver.try_recv() {
+                event = newer;
+            }
+        }
+
+        let result = match &event {
+            DaemonEvent::State(state) => sink.handle(state).await,
+            DaemonEvent::Disconnected => sink.disconnected().await,
+        };
+        match result {
+            Ok(()) => failures = 0,
+            Err(e) => {
+                failures += 1;
+                warn!("{} sink failed: {}", sink.name(), e);
+                if failures >= MAX_SINK_FAILURES {
+                    error!("Stopping {} sink after {} failures in a row", sink.name(), failures);
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Feeds the virtual gamepad, and passes rumble from games back to the
+/// controller when given a request sender.
+///
+/// The gamepad is created for the model of the first state, and replaced when
+/// a controller of the other model takes over, since the two have different
+/// buttons.
+pub struct GamepadSink {
+    gamepad: Option<VirtualGamepad>,
+    player: Option<u8>,
+    force_feedback: bool,
+    settings: Option<DeviceConfig>,
+    /// Model the gamepad couldn't be created for, so it isn't retried on every state
+    failed: Option<ControllerModel>,
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    requests: Option<mpsc::Sender<ControllerRequest>>,
+}
+
+/// A virtual gamepad made for one controller model.
+struct VirtualGamepad {
+    handler: Arc<Mutex<GamepadHandler>>,
+    model: ControllerModel,
+    /// Answers force feedback from games, see [`GamepadSink::with_requests`]
+    rumble: Option<JoinHandle<()>>,
+}
+
+impl Drop for VirtualGamepad {
+    fn drop(&mut self) {
+        if let Some(rumble) = &self.rumble {
+            rumble.abort();
+        }
+    }
+}
+
+impl GamepadSink {
+    /// `player` and `force_feedback` are passed on to [`GamepadHandler::new`].
+    pub fn new(player: Option<u8>, force_feedback: bool) -> Self {
+        Self { gamepad: None, player, force_feedback, settings: None, failed: None, requests: None }
+    }
+
+    /// Apply the controller's button mapping and calibration before each update.
+    pub fn with_settings(mut self, settings: DeviceConfig) -> Self {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Read force feedback from games in a task of its own, woken whenever
+    /// the gamepad has some, so effect uploads are answered at once even while
+    /// no states arrive because the controller is away. Rumble is only read
+    /// on Linux; ViGEm's isn't forwarded yet. The task starts with each
+    /// gamepad, inside the sink's task.
+    pub fn with_requests(mut self, requests: mpsc::Sender<ControllerRequest>) -> Self {
+        self.requests = Some(requests);
+        self
+    }
+
+    /// The gamepad for `model`, unplugging one made for another model first.
+    /// None when it couldn't be created, which is only tried once per model.
+    fn gamepad_for(&mut self, model: ControllerModel) -> Option<&VirtualGamepad> {
+        match &self.gamepad {
+            Some(gamepad) if gamepad.model == model => {}
+            Some(gamepad) => {
+                info!("Controller changed from {} to {}, replacing the virtual gamepad", gamepad.model, model);
+                self.gamepad = None;
+            }
+            None if self.failed == Some(model) => return None,
+            None => info!("Initializing virtual gamepad..."),
+        }
+        if self.gamepad.is_none() {
+            let handler = match GamepadHandler::new(model, self.player, self.force_feedback) {
+                Ok(handler) => Arc::new(Mutex::new(handler)),
+                Err(e) => {
+                    error!("Failed to initialize virtual gamepad: {}", e);
+                    self.failed = Some(model);
+                    return None;
+                }
+            };
+            #[cfg(target_os = "linux")]
+            let rumble = self.requests.clone().map(|requests| tokio::spawn(forward_rumble(handler.clone(), requests)));
+            #[cfg(not(target_os = "linux"))]
+            let rumble = None;
+            self.failed = None;
+            self.gamepad = Some(VirtualGamepad { handler, model, rumble });
+        }
+        self.gamepad.as_ref()
+    }
+}
+
+/// Answer force feedback from games whenever the gamepad has some, and turn
+/// each effect started into a rumble request.
+#[cfg(target_os = "linux")]
+async fn forward_rumble(handler: Arc<Mutex<GamepadHandler>>, requests: mpsc::Sender<ControllerRequest>) {
+    use std::os::fd::AsRawFd;
+    use tokio::io::unix::AsyncFd;
+    use tokio::io::Interest;
+
+    let fd = handler.lock().unwrap().as_raw_fd();
+    let fd = match AsyncFd::with_interest(fd, Interest::READABLE) {
+        Ok(fd) => fd,
+        Err(e) => {
+            error!("Failed to watch the gamepad for force feedback: {}", e);
+            return;
+        }
+    };
+
+    loop {
+        let mut ready = match fd.readable().await {
+            Ok(ready) => ready,
+            Err(e) => {
+                error!("Failed to wait for force feedback: {}", e);
+                return;
+            }
+        };
+        // Reading until it would block clears the readiness for the next wait
+        match ready.try_io(|_| handler.lock().unwrap().read_rumble()) {
+            Ok(Ok(patterns)) => {
+                for pattern in patterns {
+                    if requests.try_send(ControllerRequest::Rumble(pattern)).is_err() {
+                        debug!("Controller is busy, dropped a rumble");
+                    }
+                }
+            }
+            Ok(Err(e)) => {
+                error!("Stopped reading force feedback: {}", e);
+                return;
+            }
+            Err(_would_block) => {}
+        }
+    }
+}
+
+#[async_trait]
+impl StateSink for GamepadSink {
+    fn name(&self) -> &str {
+        "gamepad"
+    }
+
+    async fn handle(&mut self, state: &ControllerState) -> Result<()> {
+        let Some(gamepad) = self.gamepad_for(state.model) else {
+            return Ok(());
+        };
+        let handler = gamepad.handler.clone();
+        let mut handler = handler.lock().unwrap();
+        match &self.settings {
+            Some(settings) => {
+                let mut state = *state;
+                settings.apply(&mut state);
+                handler.update(&state)
+            }
+            None => handler.update(state),
+        }
+    }
+
+    /// Centre the sticks and release every button, so the gamepad stays
+    /// plugged in but idle until the controller is back.
+    async fn disconnected(&mut self) -> Result<()> {
+        match &self.gamepad {
+            Some(gamepad) => gamepad.handler.lock().unwrap().update(&ControllerState::neutral(gamepad.model)),
+            // No gamepad until the first state
+            None => Ok(()),
+        }
+    }
+
+    fn latest_only(&self) -> bool {
+        true
+    }
+}
+
+/// Rewrites a status line on stdout.
+pub struct ConsoleSink {
+    stdout: Stdout,
+}
+
+impl ConsoleSink {
+    pub fn new() -> Self {
+        Self { stdout: tokio::io::stdout() }
+    }
+}
+
+impl Default for ConsoleSink {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl StateSink for ConsoleSink {
+    fn name(&self) -> &str {
+        "console"
+    }
+
+    async fn handle(&mut self, state: &ControllerState) -> Result<()> {
+        self.stdout.write_all(format!("\r{}   ", state).as_bytes()).await?;
+        self.stdout.flush().await?;
+        Ok(())
+    }
+
+    async fn disconnected(&mut self) -> Result<()> {
+        self.stdout.write_all(b"\nWaiting for the controller...\n").await?;
+        self.stdout.flush().await?;
+        Ok(())
+    }
+
+    fn latest_only(&self) -> bool {
+        true
+    }
+}
+
+/// Sends every state as a UDP datagram holding the 14-byte `GetState` reply
+/// payload.
+pub struct UdpSink {
+    socket: UdpSocket,
+    target: SocketAddr,
+}
+
+impl UdpSink {
+    pub async fn new(target: SocketAddr) -> Result<Self> {
+        let bind: SocketAddr = if target.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+        let socket = UdpSocket::bind(bind).await?;
+        Ok(Self { socket, target })
+    }
+}
+
+#[async_trait]
+impl StateSink for UdpSink {
+    fn name(&self) -> &str {
+        "udp"
+    }
+
+    async fn handle(&mut self, state: &ControllerState) -> Result<()> {
+        self.socket.send_to(&state.to_payload(), self.target).await?;
+        Ok(())
+    }
+}
@@ -1,5 +1,7 @@
+//! Waiting for serial devices to be plugged in or removed.
+
 #[cfg(target_os = "windows")]
-mod device_monitor {
+mod win32 {
     use windows::{
         core::*,
         Win32::Foundation::*,
@@ -9,8 +11,30 @@ mod device_monitor {
         Win32::Devices::DeviceAndDriverInstallation::*,
     };
     use std::ptr;
+    use std::time::Duration;
+
+    /// Watches for USB devices being plugged in or removed.
+    ///
+    /// Windows sends device notifications to a window, which has to be pumped
+    /// on the thread that created it, so a window is only open during
+    /// [`DeviceMonitor::wait`]. A change between waits is picked up by the
+    /// retry after the next timeout.
+    pub struct DeviceMonitor;
+
+    impl DeviceMonitor {
+        pub fn new() -> Self {
+            Self
+        }
+
+        /// Block until a device is plugged in or removed, or `timeout` passes.
+        /// Returns whether a device changed.
+        pub fn wait(&self, timeout: Duration) -> bool {
+            wait_for_device_change(timeout)
+        }
+    }
 
-    pub fn wait_for_device_change() {
+    fn wait_for_device_change(timeout: Duration) -> bool {
+        let mut changed = false;
         unsafe {
             let instance = GetModuleHandleA(None).unwrap();
             let class_name = s!("DeviceMonitorClass");
@@ -54,6 +78,9 @@ mod device_monitor {
                 DEVICE_NOTIFY_WINDOW_HANDLE,
             );
 
+            // Posts WM_TIMER once the timeout is up
+            let timer = SetTimer(hwnd, 1, timeout.as_millis().min(u32::MAX as u128) as u32, None);
+
             let mut msg = MSG::default();
             // Wait for message. GetMessage blocks.
             // We only want to wait until we get a device change, then return.
@@ -70,6 +97,10 @@ mod device_monitor {
                     // Device change detected!
                     // We could check wParam to see if it is arrival (DBT_DEVICEARRIVAL = 0x8000)
                     // But for now, any change is a good reason to check for our device.
+                    changed = true;
+                    break;
+                }
+                if msg.message == WM_TIMER {
                     break;
                 }
 
@@ -77,11 +108,15 @@ mod device_monitor {
                 DispatchMessageA(&msg);
             }
             
+            if timer != 0 {
+                let _ = KillTimer(hwnd, 1);
+            }
             if !notify_handle.is_invalid() {
                 UnregisterDeviceNotification(notify_handle);
             }
             DestroyWindow(hwnd);
         }
+        changed
     }
 
     unsafe extern "system" fn wnd_proc(
@@ -94,12 +129,129 @@ mod device_monitor {
     }
 }
 
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::ffi::CStr;
+    use std::io;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::time::Duration;
+    use tracing::debug;
+
+    /// Device nodes are created and removed in /dev by udev, which changes
+    /// their permissions right after creating them
+    const DEV: &CStr = c"/dev";
+    const DEV_EVENTS: u32 = libc::IN_CREATE | libc::IN_DELETE | libc::IN_ATTRIB;
+
+    /// Watches /dev for device nodes being created or removed.
+    ///
+    /// The watch stays open from [`DeviceMonitor::new`] on, so a change
+    /// between two waits is still reported by the next one.
+    pub struct DeviceMonitor {
+        /// `None` when inotify isn't available, in which case waits just sleep
+        inotify: Option<OwnedFd>,
+    }
+
+    impl DeviceMonitor {
+        pub fn new() -> Self {
+            match watch_dev() {
+                Ok(inotify) => Self { inotify: Some(inotify) },
+                Err(e) => {
+                    // Without inotify, waiting out the timeout is as good as it gets
+                    debug!("Failed to watch /dev, sleeping instead: {}", e);
+                    Self { inotify: None }
+                }
+            }
+        }
+
+        /// Block until a device is plugged in or removed, or `timeout` passes.
+        /// Returns whether a device changed.
+        pub fn wait(&self, timeout: Duration) -> bool {
+            let Some(inotify) = &self.inotify else {
+                std::thread::sleep(timeout);
+                return false;
+            };
+            match wait_readable(inotify, timeout) {
+                Ok(changed) => {
+                    if changed {
+                        // Any change is a good reason to check for our device, so
+                        // the events are only read to clear them for the next wait
+                        drain(inotify);
+                    }
+                    changed
+                }
+                Err(e) => {
+                    debug!("Failed to wait for /dev changes, sleeping instead: {}", e);
+                    std::thread::sleep(timeout);
+                    false
+                }
+            }
+        }
+    }
+
+    fn watch_dev() -> io::Result<OwnedFd> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC | libc::IN_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let inotify = unsafe { OwnedFd::from_raw_fd(fd) };
+        if unsafe { libc::inotify_add_watch(inotify.as_raw_fd(), DEV.as_ptr(), DEV_EVENTS) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(inotify)
+    }
+
+    fn wait_readable(inotify: &OwnedFd, timeout: Duration) -> io::Result<bool> {
+        let mut fds = libc::pollfd {
+            fd: inotify.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout_ms = timeout.as_millis().min(libc::c_int::MAX as u128) as libc::c_int;
+        loop {
+            match unsafe { libc::poll(&mut fds, 1, timeout_ms) } {
+                rc if rc < 0 => {
+                    let e = io::Error::last_os_error();
+                    if e.kind() != io::ErrorKind::Interrupted {
+                        return Err(e);
+                    }
+                }
+                rc => return Ok(rc > 0),
+            }
+        }
+    }
+
+    /// Read every queued event. The fd is non-blocking, so this stops once
+    /// the queue is empty.
+    fn drain(inotify: &OwnedFd) {
+        let mut buffer = [0u8; 4096];
+        while unsafe { libc::read(inotify.as_raw_fd(), buffer.as_mut_ptr().cast(), buffer.len()) } > 0 {}
+    }
+}
+
 #[cfg(target_os = "windows")]
-pub use device_monitor::wait_for_device_change;
+pub use win32::DeviceMonitor;
 
-#[cfg(not(target_os = "windows"))]
-pub fn wait_for_device_change() {
-    // On non-Windows, we don't have a specific event wait implementation here yet.
-    // But the main loop logic will handle it differently.
-    std::thread::sleep(std::time::Duration::from_secs(1));
+#[cfg(target_os = "linux")]
+pub use linux::DeviceMonitor;
+
+/// No device events to wait for here, so waits just sleep out the timeout.
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+pub struct DeviceMonitor;
+
+#[cfg(not(any(target_os = "windows", target_os = "linux")))]
+impl DeviceMonitor {
+    pub fn new() -> Self {
+        Self
+    }
+
+    pub fn wait(&self, timeout: std::time::Duration) -> bool {
+        std::thread::sleep(timeout);
+        false
+    }
+}
+
+impl Default for DeviceMonitor {
+    fn default() -> Self {
+        Self::new()
+    }
 }
@@ -0,0 +1,279 @@
+use crate::frame::FrameError;
+use crate::model::ControllerModel;
+use crate::protocol::Command;
+use std::fmt;
+use std::io;
+
+/// NACK codes sent back in place of `ACK` by system commands
+pub const NACK_GENERAL: u8 = 0xFF;
+pub const NACK_PACKET_CRC: u8 = 0xCE;
+pub const NACK_PAYLOAD_TOO_SMALL: u8 = 0xD0;
+pub const NACK_TRANSFER_TOO_LARGE: u8 = 0xD1;
+pub const NACK_PROGRAM_CRC: u8 = 0xD2;
+pub const NACK_PROGRAM_FILE: u8 = 0xD3;
+pub const NACK_NO_TRANSFER: u8 = 0xD4;
+pub const NACK_INVALID_INIT: u8 = 0xD5;
+pub const NACK_ALIGNMENT: u8 = 0xD6;
+pub const NACK_ADDRESS: u8 = 0xD7;
+pub const NACK_LENGTH: u8 = 0xD8;
+
+fn describe_nack(code: u8) -> &'static str {
+    match code {
+        NACK_GENERAL => "general failure",
+        NACK_PACKET_CRC => "packet CRC error",
+        NACK_PAYLOAD_TOO_SMALL => "payload too small",
+        NACK_TRANSFER_TOO_LARGE => "transfer too large",
+        NACK_PROGRAM_CRC => "program CRC error",
+        NACK_PROGRAM_FILE => "program file error",
+        NACK_NO_TRANSFER => "no transfer open",
+        NACK_INVALID_INIT => "invalid transfer init",
+        NACK_ALIGNMENT => "data not 4-byte aligned",
+        NACK_ADDRESS => "address mismatch",
+        NACK_LENGTH => "length mismatch",
+        _ => "unknown code",
+    }
+}
+
+/// The only replies seen answered a different command than the one sent,
+/// for example a late reply to an earlier command.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResponseMismatch {
+    /// Command and subcommand that were sent
+    pub expected: (u8, u8),
+    /// Command and subcommand of the last reply dropped
+    pub received: (u8, Option<u8>),
+}
+
+impl fmt::Display for ResponseMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expected reply to {:02X} {:02X}, got {:02X} ", self.expected.0, self.expected.1, self.received.0)?;
+        match self.received.1 {
+            Some(sub) => write!(f, "{:02X}", sub),
+            None => write!(f, "with no subcommand"),
+        }
+    }
+}
+
+impl std::error::Error for ResponseMismatch {}
+
+/// Why a command to the controller failed.
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// No reply arrived in time
+    Timeout,
+    /// Replies arrived but failed their CRC check
+    Crc(FrameError),
+    /// The controller rejected a system command
+    Nack { command: Command, code: u8 },
+    /// The reply was too short or otherwise couldn't be decoded
+    Parse(&'static str),
+    /// The only replies answered a different command
+    Mismatch(ResponseMismatch),
+    /// The port failed, usually because the controller was unplugged
+    Io(io::Error),
+    /// The command needs hardware this controller model doesn't have; it wasn't sent
+    Unsupported { model: ControllerModel, feature: &'static str },
+    /// The command is experimental and experimental commands weren't enabled; it wasn't sent
+    Experimental(&'static str),
+}
+
+impl ProtocolError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            Self::Timeout => ErrorKind::Timeout,
+            Self::Crc(_) => ErrorKind::Crc,
+            Self::Nack { .. } => ErrorKind::Nack,
+            Self::Parse(_) => ErrorKind::Parse,
+            Self::Mismatch(_) => ErrorKind::Mismatch,
+            Self::Io(_) => ErrorKind::Io,
+            Self::Unsupported { .. } => ErrorKind::Unsupported,
+            Self::Experimental(_) => ErrorKind::Experimental,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Timeout => write!(f, "Timeout waiting for response"),
+            Self::Crc(e) => write!(f, "Corrupted response: {}", e),
+            Self::Nack { command, code } => {
+                write!(f, "{:?} was rejected with code {:02X} ({})", command, code, describe_nack(*code))
+            }
+            Self::Parse(what) => write!(f, "Invalid {}", what),
+            Self::Mismatch(e) => e.fmt(f),
+            Self::Io(e) => write!(f, "Transport error: {}", e),
+            Self::Unsupported { model, feature } => write!(f, "The {} has no {}", model, feature),
+            Self::Experimental(feature) => write!(f, "The {} command is experimental and isn't enabled", feature),
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Crc(e) => Some(e),
+            Self::Mismatch(e) => Some(e),
+            Self::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ProtocolError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Fieldless mirror of [`ProtocolError`], used to pick a [`RetryAction`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    Timeout,
+    Crc,
+    Nack,
+    Parse,
+    Mismatch,
+    Io,
+    Unsupported,
+    Experimental,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryAction {
+    /// Send the command again on the same connection
+    Retry,
+    /// Close the port and open it again
+    Reconnect,
+    /// Give up and report the error
+    Fail,
+}
+
+/// Decides how to recover from a failed command, by error kind.
+///
+/// The default treats timeouts, CRC failures and mismatched replies as worth
+/// retrying, reconnects on I/O errors and fails on anything else. It allows no
+/// retries until a count is given with [`RetryPolicy::with_max_retries`].
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_retries: u32,
+    actions: Vec<(ErrorKind, RetryAction)>,
+}
+
+impl RetryPolicy {
+    /// A policy that fails on every error until actions are added with [`RetryPolicy::on`].
+    pub fn new(max_retries: u32) -> Self {
+        Self { max_retries, actions: Vec::new() }
+    }
+
+    /// Use `action` for errors of `kind`.
+    pub fn on(mut self, kind: ErrorKind, action: RetryAction) -> Self {
+        self.actions.retain(|(k, _)| *k != kind);
+        self.actions.push((kind, action));
+        self
+    }
+
+    /// Keep the actions but allow `max_retries` retries.
+    pub fn with_max_retries(mut self, max_retries: u32) -> Self {
+        self.max_retries = max_retries;
+        self
+    }
+
+    pub fn max_retries(&self) -> u32 {
+        self.max_retries
+    }
+
+    pub fn action_for(&self, kind: ErrorKind) -> RetryAction {
+        self.actions
+            .iter()
+            .find(|(k, _)| *k == kind)
+            .map_or(RetryAction::Fail, |(_, action)| *action)
+    }
+
+    /// What to do after `error` ended attempt number `attempt`, counting from 1.
+    /// Retries turn into `Fail` once `max_retries` of them have been used.
+    pub fn decide(&self, error: &ProtocolError, attempt: u32) -> RetryAction {
+        match self.action_for(error.kind()) {
+            RetryAction::Retry if attempt > self.max_retries => RetryAction::Fail,
+            action => action,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self::new(0)
+            .on(ErrorKind::Timeout, RetryAction::Retry)
+            .on(ErrorKind::Crc, RetryAction::Retry)
+            .on(ErrorKind::Mismatch, RetryAction::Retry)
+            .on(ErrorKind::Io, RetryAction::Reconnect)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One error of every variant, with the kind it should report
+    fn every_error() -> Vec<(ProtocolError, ErrorKind)> {
+        vec![
+            (ProtocolError::Timeout, ErrorKind::Timeout),
+            (ProtocolError::Crc(FrameError::BadCrc { expected: 1, received: 2 }), ErrorKind::Crc),
+            (ProtocolError::Nack { command: Command::FileExit, code: NACK_PROGRAM_CRC }, ErrorKind::Nack),
+            (ProtocolError::Parse("state payload"), ErrorKind::Parse),
+            (ProtocolError::Mismatch(ResponseMismatch { expected: (0x58, 0x60), received: (0x58, Some(0x67)) }), ErrorKind::Mismatch),
+            (ProtocolError::Io(io::Error::from(io::ErrorKind::BrokenPipe)), ErrorKind::Io),
+            (ProtocolError::Unsupported { model: ControllerModel::IqGen2, feature: "rumble motor" }, ErrorKind::Unsupported),
+            (ProtocolError::Experimental("rumble"), ErrorKind::Experimental),
+        ]
+    }
+
+    #[test]
+    fn every_error_reports_its_kind() {
+        for (error, kind) in every_error() {
+            assert_eq!(error.kind(), kind, "{}", error);
+        }
+    }
+
+    #[test]
+    fn default_policy_acts_on_each_kind() {
+        let policy = RetryPolicy::default().with_max_retries(3);
+        for (error, kind) in every_error() {
+            let expected = match kind {
+                ErrorKind::Timeout | ErrorKind::Crc | ErrorKind::Mismatch => RetryAction::Retry,
+                ErrorKind::Io => RetryAction::Reconnect,
+                ErrorKind::Nack | ErrorKind::Parse | ErrorKind::Unsupported | ErrorKind::Experimental => RetryAction::Fail,
+            };
+            assert_eq!(policy.action_for(kind), expected, "{:?}", kind);
+            assert_eq!(policy.decide(&error, 1), expected, "{}", error);
+        }
+    }
+
+    #[test]
+    fn retries_stop_once_the_budget_is_spent() {
+        let policy = RetryPolicy::default().with_max_retries(2);
+        let crc = ProtocolError::Crc(FrameError::BadCrc { expected: 1, received: 2 });
+        for error in [ProtocolError::Timeout, crc] {
+            assert_eq!(policy.decide(&error, 1), RetryAction::Retry);
+            assert_eq!(policy.decide(&error, 2), RetryAction::Retry);
+            assert_eq!(policy.decide(&error, 3), RetryAction::Fail);
+        }
+
+        // Reconnecting isn't a retry, so it isn't limited by the budget
+        let io = ProtocolError::Io(io::Error::from(io::ErrorKind::BrokenPipe));
+        assert_eq!(policy.decide(&io, 10), RetryAction::Reconnect);
+    }
+
+    #[test]
+    fn default_policy_allows_no_retries() {
+        assert_eq!(RetryPolicy::default().decide(&ProtocolError::Timeout, 1), RetryAction::Fail);
+    }
+
+    #[test]
+    fn actions_can_be_replaced() {
+        let policy = RetryPolicy::default().on(ErrorKind::Timeout, RetryAction::Reconnect).on(ErrorKind::Nack, RetryAction::Retry);
+        assert_eq!(policy.action_for(ErrorKind::Timeout), RetryAction::Reconnect);
+        assert_eq!(policy.action_for(ErrorKind::Nack), RetryAction::Retry);
+        assert_eq!(RetryPolicy::new(5).action_for(ErrorKind::Timeout), RetryAction::Fail);
+    }
+}
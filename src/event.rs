@@ -0,0 +1,99 @@
+use crate::error::ProtocolError;
+use crate::protocol::{Axis, Button, ControllerState, VexController};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// A change between two successive controller states.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControllerEvent {
+    ButtonPressed(Button),
+    ButtonReleased(Button),
+    /// An axis has a new raw value, 0 to 255 with 127 at centre
+    AxisMoved { axis: Axis, value: u8 },
+    /// Battery level in percent
+    BatteryChanged(u8),
+    /// Joystick calibration started, finished, or one of the sticks is done
+    CalibrationProgress { active: bool, left_done: bool, right_done: bool },
+}
+
+impl ControllerEvent {
+    /// Events that turn `previous` into `current`: buttons first, then axes,
+    /// battery and calibration.
+    pub fn between(previous: &ControllerState, current: &ControllerState) -> Vec<ControllerEvent> {
+        let mut events = Vec::new();
+
+        for button in Button::ALL {
+            match (previous.pressed(button), current.pressed(button)) {
+                (false, true) => events.push(Self::ButtonPressed(button)),
+                (true, false) => events.push(Self::ButtonReleased(button)),
+                _ => {}
+            }
+        }
+
+        for axis in Axis::ALL {
+            let value = current.axis(axis);
+            if previous.axis(axis) != value {
+                events.push(Self::AxisMoved { axis, value });
+            }
+        }
+
+        if previous.battery != current.battery {
+            events.push(Self::BatteryChanged(current.battery));
+        }
+
+        let calibration = |state: &ControllerState| (state.cal_active, state.cal_left, state.cal_right);
+        if calibration(previous) != calibration(current) {
+            events.push(Self::CalibrationProgress {
+                active: current.cal_active,
+                left_done: current.cal_left,
+                right_done: current.cal_right,
+            });
+        }
+
+        events
+    }
+}
+
+/// Blocking iterator over controller events, see [`VexController::events`].
+pub struct Events<'a> {
+    controller: &'a mut VexController,
+    interval: Duration,
+    next_poll: Instant,
+    previous: Option<ControllerState>,
+    pending: VecDeque<ControllerEvent>,
+}
+
+impl<'a> Events<'a> {
+    pub(crate) fn new(controller: &'a mut VexController, interval: Duration) -> Self {
+        Self { controller, interval, next_poll: Instant::now(), previous: None, pending: VecDeque::new() }
+    }
+}
+
+impl Iterator for Events<'_> {
+    type Item = Result<ControllerEvent, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(event) = self.pending.pop_front() {
+                return Some(Ok(event));
+            }
+
+            let now = Instant::now();
+            if self.next_poll > now {
+                std::thread::sleep(self.next_poll - now);
+            }
+            // Skip missed polls rather than bursting to catch up
+            self.next_poll = (self.next_poll + self.interval).max(Instant::now());
+
+            match self.controller.get_state() {
+                Ok(state) => {
+                    if let Some(previous) = &self.previous {
+                        self.pending.extend(ControllerEvent::between(previous, &state));
+                    }
+                    self.previous = Some(state);
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
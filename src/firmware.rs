@@ -0,0 +1,278 @@
+use crate::protocol::{Command, VexController, CRC32};
+use anyhow::{bail, Context, Result};
+use byteorder::{ByteOrder, LittleEndian};
+use std::fmt;
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+// The FileInit layout below follows the V5 brain's file transfer protocol and
+// hasn't been confirmed for controller firmware, so uploads are experimental
+const OPERATION_WRITE: u8 = 1;
+const TARGET_FLASH: u8 = 1;
+const VID_SYSTEM: u8 = 15;
+const OPTION_OVERWRITE: u8 = 1;
+const FILE_TYPE: [u8; 4] = *b"bin\0";
+const NAME_LEN: usize = 24;
+
+/// Seconds between the Unix epoch and the VEX epoch (2000-01-01)
+const VEX_EPOCH_OFFSET: u64 = 946_684_800;
+
+/// Largest chunk sent in one `FileWrite`, whatever the controller allows
+const MAX_CHUNK: usize = 4096;
+
+/// What the controller should do when a transfer ends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransferExit {
+    /// Verify the image and keep it
+    Finish = 0x00,
+    /// Verify the image, keep it and reboot into it
+    Reboot = 0x01,
+    /// Discard everything written so far
+    Abort = 0xFF,
+}
+
+#[derive(Debug, Clone)]
+pub struct UpdateOptions {
+    /// Name stored with the image, truncated to 23 bytes
+    pub name: String,
+    /// Flash address the image is written to
+    pub address: u32,
+    /// Byte offset to resume an interrupted upload from.
+    ///
+    /// Unverified: resuming assumes the controller keeps the bytes already
+    /// written when the same image is opened again with `FileInit`. Only the
+    /// simulator is known to. If the controller doesn't, the final CRC check
+    /// rejects the image and it has to be sent again from 0.
+    pub resume_from: usize,
+    /// Attempts per chunk before giving up
+    pub retries: u32,
+    /// Reboot into the new firmware once it is verified
+    pub reboot: bool,
+}
+
+impl Default for UpdateOptions {
+    fn default() -> Self {
+        Self {
+            name: "firmware.bin".to_string(),
+            address: 0,
+            resume_from: 0,
+            retries: 3,
+            reboot: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Progress {
+    pub written: usize,
+    pub total: usize,
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    pub fn percent(&self) -> f64 {
+        if self.total == 0 {
+            100.0
+        } else {
+            self.written as f64 * 100.0 / self.total as f64
+        }
+    }
+}
+
+/// An upload that stopped part way. `offset` is where it can be resumed from.
+#[derive(Debug)]
+pub struct UploadInterrupted {
+    pub offset: usize,
+    pub source: anyhow::Error,
+}
+
+impl fmt::Display for UploadInterrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Upload interrupted at byte {}: {}", self.offset, self.source)
+    }
+}
+
+impl std::error::Error for UploadInterrupted {}
+
+/// An open file transfer session on the controller.
+pub struct FirmwareUpload<'a> {
+    controller: &'a mut VexController,
+    image: &'a [u8],
+    address: u32,
+    chunk_size: usize,
+    offset: usize,
+}
+
+impl<'a> FirmwareUpload<'a> {
+    /// Open a transfer with `FileInit`. The controller checks the CRC32 sent
+    /// here against the received image when the transfer ends.
+    ///
+    /// Experimental: fails unless experimental commands are enabled, see
+    /// [`VexControllerBuilder::experimental`](crate::protocol::VexControllerBuilder::experimental).
+    pub fn begin(controller: &'a mut VexController, image: &'a [u8], options: &UpdateOptions) -> Result<Self> {
+        controller.check_experimental("firmware update")?;
+        if options.resume_from > image.len() {
+            bail!("Resume offset {} is past the end of the {} byte image", options.resume_from, image.len());
+        }
+        if !options.resume_from.is_multiple_of(4) {
+            bail!("Resume offset {} is not a multiple of 4", options.resume_from);
+        }
+        let length = u32::try_from(image.len())
+            .ok()
+            .filter(|&length| options.address.checked_add(length).is_some())
+            .with_context(|| format!("A {} byte image doesn't fit above address {:#010X}", image.len(), options.address))?;
+        if options.resume_from > 0 {
+            warn!("Resuming at byte {}, assuming the controller kept what was written before", options.resume_from);
+        }
+
+        let mut data = vec![0u8; 52];
+        data[0] = OPERATION_WRITE;
+        data[1] = TARGET_FLASH;
+        data[2] = VID_SYSTEM;
+        data[3] = OPTION_OVERWRITE;
+        LittleEndian::write_u32(&mut data[4..8], length);
+        LittleEndian::write_u32(&mut data[8..12], options.address);
+        LittleEndian::write_u32(&mut data[12..16], CRC32.checksum(image));
+        data[16..20].copy_from_slice(&FILE_TYPE);
+        LittleEndian::write_u32(&mut data[20..24], vex_timestamp());
+        // Bytes 24..28 hold the image version, unused for firmware
+        let name = options.name.as_bytes();
+        let name_len = name.len().min(NAME_LEN - 1);
+        data[28..28 + name_len].copy_from_slice(&name[..name_len]);
+
+        let reply = controller.send_system_command(Command::FileInit, &data)?;
+        if reply.len() < 2 {
+            bail!("Invalid FileInit reply");
+        }
+
+        let max_packet_size = LittleEndian::read_u16(&reply[0..2]) as usize;
+        // Chunks must be 4-byte aligned and leave room for the address
+        let chunk_size = (max_packet_size.saturating_sub(4) & !3).min(MAX_CHUNK);
+        if chunk_size == 0 {
+            bail!("Controller reported unusable packet size {}", max_packet_size);
+        }
+        debug!("File transfer open, {} byte chunks", chunk_size);
+
+        Ok(Self {
+            controller,
+            image,
+            address: options.address,
+            chunk_size,
+            offset: options.resume_from,
+        })
+    }
+
+    /// Bytes acknowledged by the controller so far.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn len(&self) -> usize {
+        self.image.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.image.is_empty()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.offset >= self.image.len()
+    }
+
+    /// Send the next chunk with `FileWrite`. The offset only moves on once the
+    /// controller has acknowledged it, so a failed chunk can simply be retried.
+    pub fn write_next_chunk(&mut self) -> Result<usize> {
+        let end = (self.offset + self.chunk_size).min(self.image.len());
+        let chunk = &self.image[self.offset..end];
+
+        let address = u32::try_from(self.offset)
+            .ok()
+            .and_then(|offset| self.address.checked_add(offset))
+            .with_context(|| format!("Chunk at byte {} is past the end of the address space", self.offset))?;
+
+        let mut data = Vec::with_capacity(4 + chunk.len() + 3);
+        data.extend_from_slice(&address.to_le_bytes());
+        data.extend_from_slice(chunk);
+        // Writes must be a multiple of 4 bytes
+        data.resize(4 + chunk.len().next_multiple_of(4), 0);
+
+        self.controller.send_system_command(Command::FileWrite, &data)?;
+        self.offset = end;
+        Ok(self.offset)
+    }
+
+    /// Close the transfer with `FileExit`. The controller rejects it if the
+    /// image doesn't match the CRC32 sent with `FileInit`.
+    pub fn finish(self, reboot: bool) -> Result<()> {
+        if !self.is_complete() {
+            bail!("Only {} of {} bytes were written", self.offset, self.image.len());
+        }
+        let exit = if reboot { TransferExit::Reboot } else { TransferExit::Finish };
+        self.controller.send_system_command(Command::FileExit, &[exit as u8])?;
+        Ok(())
+    }
+
+    /// Discard the transfer on the controller.
+    pub fn abort(self) -> Result<()> {
+        abort_transfer(self.controller)
+    }
+}
+
+/// Discard any open transfer, including one left behind by an earlier run.
+pub fn abort_transfer(controller: &mut VexController) -> Result<()> {
+    controller.send_system_command(Command::FileExit, &[TransferExit::Abort as u8])?;
+    Ok(())
+}
+
+/// Upload a whole firmware image, retrying failed chunks from the last
+/// acknowledged offset.
+///
+/// `progress` is called after every chunk; returning `ControlFlow::Break`
+/// aborts the transfer on the controller. When the upload fails part way the
+/// error is an [`UploadInterrupted`] carrying the offset to resume from.
+pub fn upload_firmware(
+    controller: &mut VexController,
+    image: &[u8],
+    options: &UpdateOptions,
+    mut progress: impl FnMut(&Progress) -> ControlFlow<()>,
+) -> Result<()> {
+    let start = Instant::now();
+    let mut upload = FirmwareUpload::begin(controller, image, options)?;
+
+    while !upload.is_complete() {
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match upload.write_next_chunk() {
+                Ok(_) => break,
+                Err(e) if attempt < options.retries.max(1) => {
+                    warn!("Chunk at byte {} failed (attempt {}): {}", upload.offset(), attempt, e);
+                }
+                Err(e) => {
+                    return Err(UploadInterrupted { offset: upload.offset(), source: e }.into());
+                }
+            }
+        }
+
+        let report = Progress {
+            written: upload.offset(),
+            total: upload.len(),
+            elapsed: start.elapsed(),
+        };
+        if progress(&report).is_break() {
+            upload.abort()?;
+            bail!("Update aborted");
+        }
+    }
+
+    // A rejected image has to be sent again from the start, so this isn't resumable
+    upload.finish(options.reboot).context("Firmware verification failed")
+}
+
+fn vex_timestamp() -> u32 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs().saturating_sub(VEX_EPOCH_OFFSET) as u32)
+        .unwrap_or(0)
+}
@@ -0,0 +1,331 @@
+use crate::protocol::{calculate_crc16, HEADERR, HEADERS};
+use byteorder::{BigEndian, ByteOrder};
+use std::fmt;
+
+/// Largest length field accepted by default, comfortably above any reply or
+/// file transfer chunk
+pub const DEFAULT_MAX_FRAME_LEN: usize = 8192;
+
+/// Which side of the link a byte stream comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    /// Host to device: `HEADERS`, two command bytes, length of the data, data, CRC
+    Command,
+    /// Device to host: `HEADERR`, command byte, length of the payload and CRC, payload, CRC
+    Response,
+}
+
+impl FrameKind {
+    fn header(&self) -> &'static [u8] {
+        match self {
+            Self::Command => &HEADERS,
+            Self::Response => &HEADERR,
+        }
+    }
+
+    /// Offset of the first length byte
+    fn length_offset(&self) -> usize {
+        match self {
+            Self::Command => HEADERS.len() + 2,
+            Self::Response => HEADERR.len() + 1,
+        }
+    }
+}
+
+/// A CRC-checked frame.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// First command byte
+    pub command: u8,
+    /// Everything between the length and the CRC. For commands the second
+    /// command byte is put in front, so in both directions the payload starts
+    /// with the subcommand.
+    pub payload: Vec<u8>,
+}
+
+impl Frame {
+    pub fn subcommand(&self) -> Option<u8> {
+        self.payload.first().copied()
+    }
+
+    /// Payload after the subcommand byte.
+    pub fn data(&self) -> &[u8] {
+        self.payload.get(1..).unwrap_or_default()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// The CRC didn't match the frame contents
+    BadCrc { expected: u16, received: u16 },
+    /// The length field is larger than the decoder accepts
+    Oversized { len: usize, max: usize },
+    /// The frame ended before it could hold everything it declared
+    Truncated { expected: usize, available: usize },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BadCrc { expected, received } => {
+                write!(f, "bad CRC: expected {:04X}, received {:04X}", expected, received)
+            }
+            Self::Oversized { len, max } => write!(f, "frame length {} exceeds maximum {}", len, max),
+            Self::Truncated { expected, available } => {
+                write!(f, "truncated frame: expected {} bytes, got {}", expected, available)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+/// Incremental decoder for VEX serial frames.
+///
+/// Feed raw bytes with [`FrameDecoder::push`] and take frames out with
+/// [`FrameDecoder::next_frame`]. Bytes before a header are skipped, and after
+/// a bad frame the decoder resyncs one byte past the rejected header, so a
+/// valid frame hidden inside garbage is still found.
+pub struct FrameDecoder {
+    kind: FrameKind,
+    buffer: Vec<u8>,
+    max_len: usize,
+}
+
+impl FrameDecoder {
+    pub fn new(kind: FrameKind) -> Self {
+        Self {
+            kind,
+            buffer: Vec::new(),
+            max_len: DEFAULT_MAX_FRAME_LEN,
+        }
+    }
+
+    /// Decoder for replies read from a device.
+    pub fn responses() -> Self {
+        Self::new(FrameKind::Response)
+    }
+
+    /// Decoder for commands written by a host.
+    pub fn commands() -> Self {
+        Self::new(FrameKind::Command)
+    }
+
+    /// Reject frames whose length field is larger than `max_len`.
+    pub fn with_max_len(mut self, max_len: usize) -> Self {
+        self.max_len = max_len;
+        self
+    }
+
+    pub fn kind(&self) -> FrameKind {
+        self.kind
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.buffer.extend_from_slice(data);
+    }
+
+    /// Bytes held back waiting for the rest of a frame.
+    pub fn buffered(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
+    /// Decode the next frame, or return `None` until more bytes are pushed.
+    pub fn next_frame(&mut self) -> Option<Result<Frame, FrameError>> {
+        let header = self.kind.header();
+
+        let Some(start_idx) = self.buffer.windows(header.len()).position(|w| w == header) else {
+            // Keep a possible partial header for the next push
+            let keep = self.buffer.len().min(header.len() - 1);
+            self.buffer.drain(..self.buffer.len() - keep);
+            return None;
+        };
+        self.buffer.drain(..start_idx);
+
+        let length_offset = self.kind.length_offset();
+        if self.buffer.len() <= length_offset {
+            return None;
+        }
+
+        let (len, body_start) = if (self.buffer[length_offset] & 0x80) != 0 {
+            if self.buffer.len() <= length_offset + 1 {
+                return None;
+            }
+            let len = ((self.buffer[length_offset] & 0x7F) as usize) << 8 | (self.buffer[length_offset + 1] as usize);
+            (len, length_offset + 2)
+        } else {
+            (self.buffer[length_offset] as usize, length_offset + 1)
+        };
+
+        if len > self.max_len {
+            return Some(Err(self.reject(FrameError::Oversized { len, max: self.max_len })));
+        }
+
+        // Command lengths cover the data only, reply lengths include the CRC
+        let frame_len = match self.kind {
+            FrameKind::Command => body_start + len + 2,
+            FrameKind::Response => {
+                if len < 2 {
+                    return Some(Err(self.reject(FrameError::Truncated { expected: 2, available: len })));
+                }
+                body_start + len
+            }
+        };
+
+        if self.buffer.len() < frame_len {
+            return None;
+        }
+
+        let received = BigEndian::read_u16(&self.buffer[frame_len - 2..frame_len]);
+        let expected = calculate_crc16(&self.buffer[..frame_len - 2]);
+        if received != expected {
+            return Some(Err(self.reject(FrameError::BadCrc { expected, received })));
+        }
+
+        let command = self.buffer[header.len()];
+        let mut payload = Vec::with_capacity(frame_len - body_start - 1);
+        if self.kind == FrameKind::Command {
+            payload.push(self.buffer[header.len() + 1]);
+        }
+        payload.extend_from_slice(&self.buffer[body_start..frame_len - 2]);
+        self.buffer.drain(..frame_len);
+
+        Some(Ok(Frame { kind: self.kind, command, payload }))
+    }
+
+    /// Report a frame left incomplete at the end of the stream, such as at the
+    /// end of a capture, and discard it.
+    pub fn finish(&mut self) -> Option<FrameError> {
+        let header = self.kind.header();
+        if !self.buffer.starts_with(header) {
+            self.buffer.clear();
+            return None;
+        }
+
+        let length_offset = self.kind.length_offset();
+        let expected = match self.buffer.get(length_offset) {
+            Some(&b) if b & 0x80 == 0 => Some(length_offset + 1 + b as usize),
+            Some(&b) => self
+                .buffer
+                .get(length_offset + 1)
+                .map(|&lo| length_offset + 2 + (((b & 0x7F) as usize) << 8 | lo as usize)),
+            None => None,
+        };
+        let expected = match (self.kind, expected) {
+            (FrameKind::Command, Some(n)) => n + 2,
+            (FrameKind::Response, Some(n)) => n,
+            (_, None) => length_offset + 1,
+        };
+
+        let available = self.buffer.len();
+        self.buffer.clear();
+        Some(FrameError::Truncated { expected, available })
+    }
+
+    /// Skip past the header of a rejected frame so decoding can resync.
+    fn reject(&mut self, error: FrameError) -> FrameError {
+        self.buffer.drain(..1);
+        error
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Protocol;
+
+    fn reply(payload: &[u8]) -> Vec<u8> {
+        Protocol::encode_response(0x58, payload)
+    }
+
+    #[test]
+    fn skips_junk_before_a_frame() {
+        let mut decoder = FrameDecoder::responses();
+        decoder.push(&[0x00, 0xAA, 0x13, 0x55, 0xAA]);
+        decoder.push(&reply(&[0x60, 1, 2]));
+
+        let frame = decoder.next_frame().unwrap().unwrap();
+        assert_eq!((frame.command, frame.payload), (0x58, vec![0x60, 1, 2]));
+        assert_eq!(decoder.next_frame(), None);
+        assert_eq!(decoder.buffered(), 0);
+    }
+
+    #[test]
+    fn reads_one_and_two_byte_lengths() {
+        let long = vec![0x5A; 300];
+        let mut decoder = FrameDecoder::responses();
+        decoder.push(&reply(&[0x60]));
+        decoder.push(&reply(&long));
+
+        assert_eq!(decoder.next_frame().unwrap().unwrap().payload, vec![0x60]);
+        assert_eq!(decoder.next_frame().unwrap().unwrap().payload, long);
+
+        let mut decoder = FrameDecoder::commands();
+        decoder.push(&Protocol::encode_command(0x56, 0x13, &long));
+        let frame = decoder.next_frame().unwrap().unwrap();
+        assert_eq!((frame.subcommand(), frame.data()), (Some(0x13), &long[..]));
+    }
+
+    #[test]
+    fn rejects_oversized_frames_and_resyncs() {
+        let mut decoder = FrameDecoder::responses().with_max_len(16);
+        decoder.push(&reply(&[0x5A; 64]));
+        decoder.push(&reply(&[0x60]));
+
+        assert_eq!(decoder.next_frame(), Some(Err(FrameError::Oversized { len: 66, max: 16 })));
+        let frame = loop {
+            match decoder.next_frame() {
+                Some(Ok(frame)) => break frame,
+                Some(Err(_)) => continue,
+                None => panic!("lost the frame after an oversized one"),
+            }
+        };
+        assert_eq!(frame.payload, vec![0x60]);
+    }
+
+    #[test]
+    fn recovers_after_a_bad_crc() {
+        let mut corrupt = reply(&[0x60, 1, 2]);
+        let last = corrupt.len() - 1;
+        corrupt[last] ^= 0xFF;
+
+        let mut decoder = FrameDecoder::responses();
+        decoder.push(&corrupt);
+        decoder.push(&reply(&[0x61]));
+
+        assert!(matches!(decoder.next_frame(), Some(Err(FrameError::BadCrc { .. }))));
+        assert_eq!(decoder.next_frame().unwrap().unwrap().payload, vec![0x61]);
+        assert_eq!(decoder.next_frame(), None);
+    }
+
+    #[test]
+    fn joins_a_frame_split_across_pushes() {
+        let frame = reply(&[0x60; 200]);
+        let mut decoder = FrameDecoder::responses();
+        for chunk in frame.chunks(3) {
+            assert_eq!(decoder.next_frame(), None);
+            decoder.push(chunk);
+        }
+        assert_eq!(decoder.next_frame().unwrap().unwrap().payload, vec![0x60; 200]);
+    }
+
+    #[test]
+    fn finish_reports_a_truncated_frame() {
+        let frame = reply(&[0x60, 1, 2, 3]);
+        let mut decoder = FrameDecoder::responses();
+        decoder.push(&frame[..frame.len() - 2]);
+
+        assert_eq!(decoder.next_frame(), None);
+        assert_eq!(
+            decoder.finish(),
+            Some(FrameError::Truncated { expected: frame.len(), available: frame.len() - 2 })
+        );
+        assert_eq!(decoder.buffered(), 0);
+        assert_eq!(decoder.finish(), None);
+    }
+}
@@ -1,20 +1,37 @@
-use crate::protocol::ControllerState;
+use crate::model::ControllerModel;
+use crate::protocol::{Axis, Button, ControllerState};
 
 #[cfg(target_os = "linux")]
 mod linux {
     use super::*;
+    use crate::protocol::RUMBLE_PATTERN_MAX;
     use evdev::{
-        uinput::{VirtualDevice, VirtualDeviceBuilder},
-        AttributeSet, InputEvent, EventType, Key, AbsoluteAxisType, UinputAbsSetup, AbsInfo,
-        InputId, BusType,
+        uinput::{UInputEvent, VirtualDevice, VirtualDeviceBuilder},
+        AttributeSet, InputEvent, InputEventKind, EventType, Key, AbsoluteAxisType, UinputAbsSetup, AbsInfo,
+        InputId, BusType, FFEffectData, FFEffectKind, FFEffectType, UInputEventType,
     };
+    use std::collections::HashMap;
+    use std::io;
+    use std::os::fd::{AsRawFd, RawFd};
+
+    /// Force-feedback effects a game can have uploaded at once
+    const MAX_EFFECTS: u32 = 16;
+
+    /// Effect length each rumble pulse stands for
+    const RUMBLE_PULSE_MS: usize = 200;
 
     pub struct GamepadHandler {
         device: VirtualDevice,
+        model: ControllerModel,
+        /// Force-feedback effects uploaded by games, by effect ID
+        effects: HashMap<i16, FFEffectData>,
     }
 
     impl GamepadHandler {
-        pub fn new() -> anyhow::Result<Self> {
+        /// `player` is added to the device name so games can tell controllers apart.
+        /// Force feedback is offered to games when `rumble` is set and the model
+        /// has a rumble motor.
+        pub fn new(model: ControllerModel, player: Option<u8>, rumble: bool) -> anyhow::Result<Self> {
             let mut keys = AttributeSet::<Key>::new();
             keys.insert(Key::BTN_TL);
             // keys.insert(Key::BTN_TL2); // Mapped to ABS_Z
@@ -37,8 +54,12 @@ mod linux {
             keys.insert(Key::BTN_DPAD_LEFT);
             keys.insert(Key::BTN_DPAD_RIGHT);
 
-            let device = VirtualDeviceBuilder::new()?
-                .name("VEX IQ Gen 2 Controller")
+            let name = match player {
+                Some(player) => format!("{} (Player {})", model, player),
+                None => model.to_string(),
+            };
+            let mut builder = VirtualDeviceBuilder::new()?
+                .name(&name)
                 .input_id(InputId::new(BusType::BUS_USB, 0x045e, 0x028e, 0x110))
                 .with_keys(&keys)?
                 .with_absolute_axis(&UinputAbsSetup::new(
@@ -64,59 +85,155 @@ mod linux {
                 .with_absolute_axis(&UinputAbsSetup::new(
                     AbsoluteAxisType::ABS_RZ,
                     AbsInfo::new(0, 0, 255, 0, 0, 0),
-                ))?
-                .build()?;
+                ))?;
+
+            if rumble && model.has_rumble() {
+                let mut effects = AttributeSet::<FFEffectType>::new();
+                effects.insert(FFEffectType::FF_RUMBLE);
+                builder = builder.with_ff(&effects)?.with_ff_effects_max(MAX_EFFECTS);
+            }
+
+            let device = builder.build()?;
 
-            Ok(Self { device })
+            // Force-feedback requests are read when the device is readable, and until it would block
+            let fd = device.as_raw_fd();
+            if unsafe { libc::fcntl(fd, libc::F_SETFL, libc::fcntl(fd, libc::F_GETFL) | libc::O_NONBLOCK) } != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+
+            Ok(Self { device, model, effects: HashMap::new() })
         }
 
         pub fn update(&mut self, state: &ControllerState) -> anyhow::Result<()> {
             let mut events = Vec::new();
 
-            // Axes
-            // VEX: 0-255, 127 center.
-            // Linux ABS_X/Y: We defined 0-255.
-            // Invert Y axes to match standard gamepad (Up is negative)
-            // VEX: Up is 255 (usually), Down is 0.
-            // Standard gamepad: Up is min, Down is max.
-            // So we need to invert Y axes: 255 - value.
-            
-            // Reverted to previous configuration as requested:
-            // ABS_X/Y <- Right Stick
-            // ABS_RX/RY <- Left Stick
-            
-            events.push(InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_X.0, state.right_x as i32));
-            events.push(InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_Y.0, 255 - state.right_y as i32));
-            events.push(InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_RX.0, state.left_x as i32));
-            events.push(InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_RY.0, 255 - state.left_y as i32));
-
-            // Triggers (L2/R2) mapped to Axes
-            events.push(InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_Z.0, if state.l_down { 255 } else { 0 }));
-            events.push(InputEvent::new(EventType::ABSOLUTE, AbsoluteAxisType::ABS_RZ.0, if state.r_down { 255 } else { 0 }));
-
-            // Buttons
-            events.push(InputEvent::new(EventType::KEY, Key::BTN_TL.0, if state.l_up { 1 } else { 0 }));
-            // events.push(InputEvent::new(EventType::KEY, Key::BTN_TL2.0, if state.l_down { 1 } else { 0 }));
-            events.push(InputEvent::new(EventType::KEY, Key::BTN_TR.0, if state.r_up { 1 } else { 0 }));
-            // events.push(InputEvent::new(EventType::KEY, Key::BTN_TR2.0, if state.r_down { 1 } else { 0 }));
-            
-            events.push(InputEvent::new(EventType::KEY, Key::BTN_THUMBL.0, if state.l3 { 1 } else { 0 }));
-            events.push(InputEvent::new(EventType::KEY, Key::BTN_THUMBR.0, if state.r3 { 1 } else { 0 }));
-
-            // Action Buttons (Diamond)
-            // E Up -> Y (North)
-            // E Down -> A (South)
-            // F Up -> X (West)
-            // F Down -> B (East)
-            events.push(InputEvent::new(EventType::KEY, Key::BTN_NORTH.0, if state.e_up { 1 } else { 0 }));
-            events.push(InputEvent::new(EventType::KEY, Key::BTN_SOUTH.0, if state.e_down { 1 } else { 0 }));
-            events.push(InputEvent::new(EventType::KEY, Key::BTN_WEST.0, if state.f_up { 1 } else { 0 }));
-            events.push(InputEvent::new(EventType::KEY, Key::BTN_EAST.0, if state.f_down { 1 } else { 0 }));
+            // VEX: 0-255, 127 center, up is 255.
+            // Standard gamepad: up is min, so Y axes are inverted.
+            for (axis, code, invert) in STICK_AXES {
+                let value = state.axis(axis) as i32;
+                let value = if invert { 255 - value } else { value };
+                events.push(InputEvent::new(EventType::ABSOLUTE, code.0, value));
+            }
+
+            let (triggers, keys) = match self.model {
+                ControllerModel::IqGen2 => (IQ_GEN2_TRIGGERS, &IQ_GEN2_KEYS[..]),
+                ControllerModel::V5 => (V5_TRIGGERS, &V5_KEYS[..]),
+            };
+
+            for (button, code) in triggers {
+                events.push(InputEvent::new(EventType::ABSOLUTE, code.0, if state.pressed(button) { 255 } else { 0 }));
+            }
+
+            for &(button, key) in keys {
+                events.push(InputEvent::new(EventType::KEY, key.0, state.pressed(button) as i32));
+            }
 
             self.device.emit(&events)?;
             Ok(())
         }
+
+        /// Answer force-feedback uploads and erasures from games, and return a
+        /// rumble pattern for each effect started. Fails with `WouldBlock` once
+        /// there is nothing left to read.
+        pub fn read_rumble(&mut self) -> io::Result<Vec<String>> {
+            let events: Vec<UInputEvent> = self.device.fetch_events()?.collect();
+
+            let mut patterns = Vec::new();
+            for event in events {
+                match event.kind() {
+                    InputEventKind::UInput(code) if code == UInputEventType::UI_FF_UPLOAD.0 => {
+                        // The kernel has picked the effect ID; the upload completes when dropped
+                        let upload = self.device.process_ff_upload(event).map_err(io::Error::other)?;
+                        self.effects.insert(upload.effect_id(), upload.effect());
+                    }
+                    InputEventKind::UInput(code) if code == UInputEventType::UI_FF_ERASE.0 => {
+                        let erase = self.device.process_ff_erase(event).map_err(io::Error::other)?;
+                        self.effects.remove(&(erase.effect_id() as i16));
+                    }
+                    // A value above 0 starts the effect, 0 stops it
+                    InputEventKind::ForceFeedback(id) if event.value() > 0 => {
+                        patterns.extend(self.effects.get(&(id as i16)).and_then(rumble_pattern));
+                    }
+                    _ => {}
+                }
+            }
+            Ok(patterns)
+        }
     }
+
+    /// The uinput device, which turns readable when a game sends force feedback.
+    impl AsRawFd for GamepadHandler {
+        fn as_raw_fd(&self) -> RawFd {
+            self.device.as_raw_fd()
+        }
+    }
+
+    /// A V5 rumble pattern approximating an effect: long pulses when the strong
+    /// motor leads, short ones when the weak one does, one per 200 ms.
+    fn rumble_pattern(effect: &FFEffectData) -> Option<String> {
+        let FFEffectKind::Rumble { strong_magnitude, weak_magnitude } = effect.kind else {
+            return None;
+        };
+        if strong_magnitude == 0 && weak_magnitude == 0 {
+            return None;
+        }
+
+        let pulse = if strong_magnitude >= weak_magnitude { '-' } else { '.' };
+        // An effect without a length runs until stopped, so play the longest pattern
+        let pulses = match effect.replay.length {
+            0 => RUMBLE_PATTERN_MAX,
+            ms => (ms as usize).div_ceil(RUMBLE_PULSE_MS).min(RUMBLE_PATTERN_MAX),
+        };
+        Some(std::iter::repeat_n(pulse, pulses).collect())
+    }
+
+    /// Sticks and the gamepad axis each one drives, and whether it is inverted.
+    /// ABS_X/Y take the right stick and ABS_RX/RY the left one.
+    const STICK_AXES: [(Axis, AbsoluteAxisType, bool); 4] = [
+        (Axis::RightX, AbsoluteAxisType::ABS_X, false),
+        (Axis::RightY, AbsoluteAxisType::ABS_Y, true),
+        (Axis::LeftX, AbsoluteAxisType::ABS_RX, false),
+        (Axis::LeftY, AbsoluteAxisType::ABS_RY, true),
+    ];
+
+    /// L Down and R Down act as the analog triggers (L2/R2)
+    const IQ_GEN2_TRIGGERS: [(Button, AbsoluteAxisType); 2] = [
+        (Button::LDown, AbsoluteAxisType::ABS_Z),
+        (Button::RDown, AbsoluteAxisType::ABS_RZ),
+    ];
+
+    /// Buttons reported as keys. E and F form the action diamond:
+    /// E Up -> Y (North), E Down -> A (South), F Up -> X (West), F Down -> B (East)
+    const IQ_GEN2_KEYS: [(Button, Key); 8] = [
+        (Button::LUp, Key::BTN_TL),
+        (Button::RUp, Key::BTN_TR),
+        (Button::L3, Key::BTN_THUMBL),
+        (Button::R3, Key::BTN_THUMBR),
+        (Button::EUp, Key::BTN_NORTH),
+        (Button::EDown, Key::BTN_SOUTH),
+        (Button::FUp, Key::BTN_WEST),
+        (Button::FDown, Key::BTN_EAST),
+    ];
+
+    const V5_TRIGGERS: [(Button, AbsoluteAxisType); 2] = [
+        (Button::L2, AbsoluteAxisType::ABS_Z),
+        (Button::R2, AbsoluteAxisType::ABS_RZ),
+    ];
+
+    /// The face buttons map by position, not by label, since the V5 diamond
+    /// is X top, A right, B bottom and Y left.
+    const V5_KEYS: [(Button, Key); 10] = [
+        (Button::L1, Key::BTN_TL),
+        (Button::R1, Key::BTN_TR),
+        (Button::X, Key::BTN_NORTH),
+        (Button::A, Key::BTN_EAST),
+        (Button::B, Key::BTN_SOUTH),
+        (Button::Y, Key::BTN_WEST),
+        (Button::Up, Key::BTN_DPAD_UP),
+        (Button::Down, Key::BTN_DPAD_DOWN),
+        (Button::Left, Key::BTN_DPAD_LEFT),
+        (Button::Right, Key::BTN_DPAD_RIGHT),
+    ];
 }
 
 #[cfg(target_os = "linux")]
@@ -129,43 +246,34 @@ mod windows {
 
     pub struct GamepadHandler {
         target: X360Controller,
+        model: ControllerModel,
     }
 
     impl GamepadHandler {
-        pub fn new() -> anyhow::Result<Self> {
+        /// ViGEm hands out XInput player slots itself, so `player` is ignored.
+        pub fn new(model: ControllerModel, _player: Option<u8>, _rumble: bool) -> anyhow::Result<Self> {
             let client = Client::connect().map_err(|e| anyhow::anyhow!("Failed to connect to ViGEmBus: {:?}", e))?;
             let mut target = X360Controller::new(client, TargetId::XBOX360_WIRED);
             target.plugin().map_err(|e| anyhow::anyhow!("Failed to plugin virtual controller: {:?}", e))?;
-            Ok(Self { target })
+            Ok(Self { target, model })
         }
 
         pub fn update(&mut self, state: &ControllerState) -> anyhow::Result<()> {
             let mut report = XGamepad::default();
 
-            // Map buttons
-            // L Up -> LB
-            // L Down -> LT (Trigger)
-            // R Up -> RB
-            // R Down -> RT (Trigger)
-            
-            if state.l_up { report.buttons.raw |= XButtons::LB.raw; }
-            if state.r_up { report.buttons.raw |= XButtons::RB.raw; }
-            
-            if state.l_down { report.left_trigger = 255; }
-            if state.r_down { report.right_trigger = 255; }
-
-            if state.l3 { report.buttons.raw |= XButtons::LTHUMB.raw; }
-            if state.r3 { report.buttons.raw |= XButtons::RTHUMB.raw; }
-
-            // Diamond
-            // E Up -> Y
-            // E Down -> A
-            // F Up -> X
-            // F Down -> B
-            if state.e_up { report.buttons.raw |= XButtons::Y.raw; }
-            if state.e_down { report.buttons.raw |= XButtons::A.raw; }
-            if state.f_up { report.buttons.raw |= XButtons::X.raw; }
-            if state.f_down { report.buttons.raw |= XButtons::B.raw; }
+            let (buttons, [left_trigger, right_trigger]) = match self.model {
+                ControllerModel::IqGen2 => (&IQ_GEN2_BUTTONS[..], IQ_GEN2_TRIGGERS),
+                ControllerModel::V5 => (&V5_BUTTONS[..], V5_TRIGGERS),
+            };
+
+            for (button, xbutton) in buttons {
+                if state.pressed(*button) {
+                    report.buttons.raw |= xbutton.raw;
+                }
+            }
+
+            if state.pressed(left_trigger) { report.left_trigger = 255; }
+            if state.pressed(right_trigger) { report.right_trigger = 255; }
 
             // Joysticks
             // VEX: 0-255, 127 center.
@@ -181,15 +289,48 @@ mod windows {
                 ((val as i32 - 127) * 256) as i16
             }
 
-            report.thumb_lx = map_axis(state.right_x);
-            report.thumb_ly = map_axis(state.right_y);
-            report.thumb_rx = map_axis(state.left_x);
-            report.thumb_ry = map_axis(state.left_y);
+            report.thumb_lx = map_axis(state.axis(Axis::RightX));
+            report.thumb_ly = map_axis(state.axis(Axis::RightY));
+            report.thumb_rx = map_axis(state.axis(Axis::LeftX));
+            report.thumb_ry = map_axis(state.axis(Axis::LeftY));
 
             self.target.update(&report).map_err(|e| anyhow::anyhow!("Failed to update controller: {:?}", e))?;
             Ok(())
         }
     }
+
+    /// L Down and R Down act as the triggers
+    const IQ_GEN2_TRIGGERS: [Button; 2] = [Button::LDown, Button::RDown];
+
+    /// Buttons reported as XInput buttons. E and F form the action diamond:
+    /// E Up -> Y, E Down -> A, F Up -> X, F Down -> B
+    const IQ_GEN2_BUTTONS: [(Button, XButtons); 8] = [
+        (Button::LUp, XButtons::LB),
+        (Button::RUp, XButtons::RB),
+        (Button::L3, XButtons::LTHUMB),
+        (Button::R3, XButtons::RTHUMB),
+        (Button::EUp, XButtons::Y),
+        (Button::EDown, XButtons::A),
+        (Button::FUp, XButtons::X),
+        (Button::FDown, XButtons::B),
+    ];
+
+    const V5_TRIGGERS: [Button; 2] = [Button::L2, Button::R2];
+
+    /// The face buttons map by position, not by label, since the V5 diamond
+    /// is X top, A right, B bottom and Y left.
+    const V5_BUTTONS: [(Button, XButtons); 10] = [
+        (Button::L1, XButtons::LB),
+        (Button::R1, XButtons::RB),
+        (Button::X, XButtons::Y),
+        (Button::A, XButtons::B),
+        (Button::B, XButtons::A),
+        (Button::Y, XButtons::X),
+        (Button::Up, XButtons::UP),
+        (Button::Down, XButtons::DOWN),
+        (Button::Left, XButtons::LEFT),
+        (Button::Right, XButtons::RIGHT),
+    ];
 }
 
 #[cfg(target_os = "windows")]
@@ -200,7 +341,7 @@ pub struct GamepadHandler;
 
 #[cfg(not(any(target_os = "linux", target_os = "windows")))]
 impl GamepadHandler {
-    pub fn new() -> anyhow::Result<Self> {
+    pub fn new(_model: ControllerModel, _player: Option<u8>, _rumble: bool) -> anyhow::Result<Self> {
         Ok(Self)
     }
     pub fn update(&mut self, _state: &ControllerState) -> anyhow::Result<()> {
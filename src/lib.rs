@@ -0,0 +1,16 @@
+pub mod async_controller;
+pub mod calibration;
+pub mod config;
+pub mod daemon;
+pub mod error;
+pub mod event;
+pub mod firmware;
+pub mod frame;
+pub mod model;
+pub mod protocol;
+pub mod gamepad;
+pub mod serial;
+pub mod simulator;
+pub mod state;
+pub mod transport;
+pub mod device_monitor;
@@ -1,20 +1,24 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 use std::io::{self, Write};
-use std::time::Duration;
-use tracing::{info, error};
+use std::net::SocketAddr;
+use std::ops::ControlFlow;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
 
-mod protocol;
-mod gamepad;
-mod serial;
-mod transport;
-#[cfg(target_os = "windows")]
-mod device_monitor;
-
-use crate::protocol::{ControllerState, VexController};
-use crate::gamepad::GamepadHandler;
-#[cfg(target_os = "windows")]
-use crate::device_monitor::wait_for_device_change;
+use vex_controller::calibration::{self, CalibrationOutcome};
+use vex_controller::config::{Config, LinkConfig};
+use vex_controller::daemon::{self, DaemonTarget};
+use vex_controller::error::{ErrorKind, ProtocolError};
+use vex_controller::firmware::{self, UpdateOptions, UploadInterrupted};
+use vex_controller::protocol::{
+    Command, ControllerState, DeviceState, RawState, SystemStatus, TestData, VexController, FACTORY_PAIR_ID, SCREEN_LINES,
+};
+use vex_controller::model::{ControllerModel, DeviceId};
+use vex_controller::serial::{self, ControllerPort, PortSelector};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -23,6 +27,42 @@ struct Args {
     #[arg(short, long)]
     port: Option<String>,
 
+    /// USB serial number of the controller to use, when several are attached
+    #[arg(long, conflicts_with = "port")]
+    serial: Option<String>,
+
+    /// Config file (default: vex-controller/config.toml in the user config directory)
+    #[arg(long)]
+    config: Option<PathBuf>,
+
+    /// Milliseconds to wait for each reply
+    #[arg(long)]
+    timeout: Option<u64>,
+
+    /// Milliseconds to wait for each firmware transfer reply
+    #[arg(long)]
+    transfer_timeout: Option<u64>,
+
+    /// Times to retry a command after a timeout or corrupted reply
+    #[arg(long)]
+    retries: Option<u32>,
+
+    /// Milliseconds before the first retry, doubled for each retry after it
+    #[arg(long)]
+    backoff: Option<u64>,
+
+    /// Milliseconds between reads while waiting for a reply
+    #[arg(long)]
+    read_delay: Option<u64>,
+
+    /// Controller model, iq2 or v5 (default: detected from the USB product ID)
+    #[arg(long)]
+    model: Option<ControllerModel>,
+
+    /// Allow commands not yet confirmed on a real controller: V5 rumble, screen text and firmware updates
+    #[arg(long)]
+    experimental: bool,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -42,9 +82,88 @@ enum Commands {
         /// Monitor status continuously
         #[arg(long)]
         monitor: bool,
+        /// Show the raw state payload in hex. With --monitor, prints a line
+        /// whenever it changes and marks the bytes that changed.
+        #[arg(long)]
+        raw: bool,
     },
     /// Start the virtual gamepad daemon
-    Daemon,
+    Daemon {
+        /// Also send every state as a UDP datagram to this address
+        #[arg(long)]
+        udp: Option<SocketAddr>,
+
+        /// Don't print the status line
+        #[arg(long)]
+        quiet: bool,
+    },
+    /// Check link health by measuring factory ping round-trip times
+    Ping {
+        /// Number of pings to send
+        #[arg(short, long, default_value_t = 10)]
+        count: u32,
+
+        /// Delay between pings in milliseconds
+        #[arg(long, default_value_t = 100)]
+        interval: u64,
+    },
+    /// Factory self-test: walk through every button and stick axis
+    #[command(alias = "factory")]
+    Selftest {
+        /// Seconds to wait for each input before marking it failed
+        #[arg(long, default_value_t = 10)]
+        timeout: u64,
+    },
+    /// Upload new controller firmware (experimental, needs --experimental)
+    Update {
+        /// Firmware image to upload
+        #[arg(required_unless_present = "abort")]
+        file: Option<PathBuf>,
+
+        /// Resume an interrupted upload from this byte offset. Unverified on
+        /// hardware: assumes the controller kept the bytes already written.
+        #[arg(long, default_value_t = 0)]
+        resume_from: usize,
+
+        /// Don't reboot into the new firmware after it is verified
+        #[arg(long)]
+        no_reboot: bool,
+
+        /// Discard a transfer left open on the controller
+        #[arg(long)]
+        abort: bool,
+    },
+    /// Show or change the pair ID used to pair with a brain
+    Pair {
+        #[command(subcommand)]
+        action: PairAction,
+    },
+    /// List attached VEX devices and their serial ports
+    Devices,
+    /// Write text to the V5 controller screen (experimental, needs --experimental)
+    Display {
+        /// Up to three lines of text. Without --line they replace the whole
+        /// screen, so no lines clears it.
+        #[arg(num_args = 0..=3)]
+        lines: Vec<String>,
+
+        /// Write the lines from this line (1-3) down and leave the others
+        /// alone. The lines given have to fit below it.
+        #[arg(long, value_parser = clap::value_parser!(u8).range(1..=3))]
+        line: Option<u8>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum PairAction {
+    /// Show the current pair ID
+    Show,
+    /// Set a new pair ID
+    Set {
+        id: u8,
+    },
+    /// Set the pair ID back to the factory value, 0
+    Reset,
 }
 
 #[tokio::main]
@@ -52,96 +171,225 @@ async fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
     let args = Args::parse();
 
+    let config = match &args.config {
+        Some(path) => Config::load(path)?,
+        None => Config::load_default()?,
+    };
+    let selector = port_selector(&args, &config);
+    let link = link_config(&args, &config);
+
     // Helper to get controller
-    let get_controller = || -> Result<VexController> {
-        let transport = serial::SerialTransport::new(args.port.clone())?;
-        Ok(VexController::new(Box::new(transport)))
+    let get_controller = |found: &ControllerPort| -> Result<VexController> {
+        let model = controller_model(args.model, found);
+        let transport = serial::SerialTransport::new(Some(found.name.clone()))?;
+        Ok(link.builder().model(model).experimental(args.experimental).build(Box::new(transport)))
     };
 
+    // Listing devices doesn't open one
+    if let Some(Commands::Devices) = args.command {
+        return list_devices();
+    }
+
+    // The daemon runs on its own async connection
+    if let Some(Commands::Daemon { udp, quiet }) = args.command {
+        // Without a port or serial number, every attached controller is driven
+        let found = match &selector {
+            PortSelector::Auto => serial::find_controllers(),
+            _ => serial::find_controller(&selector).map(|port| vec![port]),
+        };
+        // With none there yet, the daemon reports it and waits
+        let found = found.unwrap_or_default();
+        let several = found.len() > 1;
+        let mut targets = Vec::new();
+        for port in found {
+            let settings = match get_controller(&port) {
+                Ok(mut controller) => device_id(&port, &mut controller).and_then(|id| {
+                    info!("Controller {} at {}", id, port.name);
+                    config.device(&id).cloned()
+                }),
+                Err(e) => {
+                    warn!("Failed to identify the controller at {}: {:#}", port.name, e);
+                    None
+                }
+            };
+            let player = settings.as_ref().and_then(|settings| settings.player);
+            targets.push(DaemonTarget {
+                model: args.model.unwrap_or_default(),
+                selector: reconnect_selector(&selector, &port),
+                settings,
+                player,
+            });
+        }
+        if targets.is_empty() {
+            // The controller can't be identified until it shows up, so only
+            // settings that don't need its pair ID apply
+            let settings = match &selector {
+                PortSelector::Serial(serial) => config.device_without_pair_id(serial).cloned(),
+                _ => None,
+            };
+            let player = settings.as_ref().and_then(|settings| settings.player);
+            targets.push(DaemonTarget { selector: selector.clone(), model: args.model.unwrap_or_default(), settings, player });
+        }
+        if several {
+            daemon::assign_players(&mut targets);
+        }
+        return daemon::run_targets(targets, link.builder().experimental(args.experimental), udp, quiet).await;
+    }
+
     if let Some(cmd) = args.command {
-        let mut controller = get_controller()?;
+        let found = serial::find_controller(&selector)?;
+        let mut controller = get_controller(&found)?;
         match cmd {
             Commands::Calibrate { abort } => {
                 if abort {
                     info!("Sending abort calibration command...");
-                    match controller.abort_calibration() {
-                        Ok(_) => {
-                            println!("Calibration aborted.");
-                        }
-                        Err(e) => error!("Failed to send command: {}", e),
-                    }
+                    controller.abort_calibration().context("Failed to send command")?;
+                    println!("Calibration aborted.");
                 } else {
                     info!("Starting calibration...");
-                    if let Err(e) = run_calibration(&mut controller) {
-                        error!("Calibration failed: {}", e);
-                    }
+                    run_calibration(&mut controller).context("Calibration failed")?;
                 }
             }
             Commands::Info => {
                 info!("Getting controller info...");
+                // Show everything that could be read before failing on what couldn't
+                let mut failed = 0;
                 match controller.get_versions() {
-                    Ok(v) => println!("Version String: {}", v),
-                    Err(e) => error!("Failed to get versions: {}", e),
+                    Ok(versions) => {
+                        println!("Version String: {}", versions);
+                        match versions.main() {
+                            Some(main) => {
+                                println!("Firmware: {}", main);
+                                if let Some(minimum) = config.min_version.filter(|&minimum| main < minimum) {
+                                    warn!("Firmware {} is older than the minimum {} set in the config", main, minimum);
+                                }
+                            }
+                            None => println!("Firmware: no version number found in the version string"),
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to get versions: {}", e);
+                        failed += 1;
+                    }
                 }
                 
                 match controller.get_pair_id() {
                     Ok(id) => println!("Pair ID: {}", id),
-                    Err(e) => error!("Failed to get pair ID: {}", e),
+                    Err(e) => {
+                        error!("Failed to get pair ID: {}", e);
+                        failed += 1;
+                    }
+                }
+                if let Some(id) = device_id(&found, &mut controller) {
+                    println!("Identity: {}", id);
+                    if config.device(&id).is_some() {
+                        println!("Device Settings: found in the config");
+                    }
+                }
+
+                // The SysStatus layout is a guess, so the raw reply goes next to the decoded fields
+                match controller.send_system_command(Command::SysStatus, &[]) {
+                    Ok(data) => {
+                        println!("System Status Bytes: {:02X?}", data);
+                        match SystemStatus::parse(&data) {
+                            Some(status) => {
+                                println!("Product (experimental): {}", status.product_type);
+                                println!("System Version (experimental): {}", status.system_version);
+                                println!("Bootloader Version (experimental): {}", status.bootloader_version);
+                                println!("System Flags (experimental): {:#010X}", status.flags);
+                            }
+                            None => warn!("System status is shorter than expected, not decoded"),
+                        }
+                    }
+                    Err(e) => {
+                        error!("Failed to get system status: {}", e);
+                        failed += 1;
+                    }
+                }
+                if failed > 0 {
+                    anyhow::bail!("{} of 3 info requests failed", failed);
                 }
             }
-            Commands::Status { monitor } => {
+            Commands::Status { monitor, raw: true } => {
+                if monitor {
+                    info!("Monitoring raw controller state...");
+                    monitor_raw_state(&mut controller)?;
+                } else {
+                    let state = controller.get_state().context("Failed to get status")?;
+                    print_raw_state(&state.raw, state.model);
+                }
+            }
+            Commands::Status { monitor, raw: false } => {
                 if monitor {
                     info!("Monitoring controller status...");
+                    let mut device_state = DeviceStateWatch::new();
                     loop {
+                        if let Some(state) = device_state.poll(&mut controller) {
+                            println!("\n{}", state);
+                        }
                         match controller.get_state() {
-                            Ok(state) => {
-                                print_controller_state(&state);
+                            Ok(state) => print_controller_state(&state),
+                            Err(e) if e.kind() == ErrorKind::Io => {
+                                return Err(e).context("Lost connection to controller");
                             }
-                            Err(_) => {}
+                            Err(e) => debug!("Failed to get status: {}", e),
                         }
                         std::thread::sleep(Duration::from_millis(20));
                     }
                 } else {
-                    match controller.get_state() {
-                        Ok(state) => {
-                            print_controller_state(&state);
-                            println!(); // Add newline since print_controller_state uses \r
+                    let state = controller.get_state().context("Failed to get status")?;
+                    print_controller_state(&state);
+                    println!(); // Add newline since print_controller_state uses \r
+
+                    let state = controller.get_device_state().context("Failed to get device state")?;
+                    println!("{}", state);
+                }
+            }
+            Commands::Daemon { .. } => unreachable!("the daemon is started above"),
+            Commands::Devices => unreachable!("devices are listed above"),
+            Commands::Ping { count, interval } => {
+                info!("Pinging controller...");
+                run_ping(&mut controller, count, Duration::from_millis(interval))?;
+            }
+            Commands::Selftest { timeout } => {
+                info!("Entering factory test mode...");
+                run_selftest(&mut controller, Duration::from_secs(timeout)).context("Self-test failed")?;
+            }
+            Commands::Update { file, resume_from, no_reboot, abort } => {
+                if abort {
+                    info!("Aborting file transfer...");
+                    firmware::abort_transfer(&mut controller).context("Failed to abort transfer")?;
+                    println!("Transfer aborted.");
+                } else if let Some(file) = file {
+                    match run_update(&mut controller, &file, resume_from, !no_reboot) {
+                        Err(e) if e.downcast_ref::<ProtocolError>().is_some_and(|e| e.kind() == ErrorKind::Experimental) => {
+                            anyhow::bail!("{}; pass --experimental to send it anyway", e)
                         }
-                        Err(e) => error!("Failed to get status: {}", e),
+                        result => result.context("Update failed")?,
                     }
                 }
             }
-            Commands::Daemon => {
-                info!("Initializing virtual gamepad...");
-                let mut gamepad_handler = match GamepadHandler::new() {
-                    Ok(h) => Some(h),
-                    Err(e) => {
-                        error!("Failed to initialize virtual gamepad: {}", e);
-                        None
-                    }
-                };
-
-                info!("Starting VEX Controller (Serial)...");
-                // Re-get controller here or reuse? Reuse is fine but we need to move it or clone.
-                // Since we are in a match arm, we own controller.
-                
-                info!("Listening for controller data...");
-
-                loop {
-                    match controller.get_state() {
-                        Ok(state) => {
-                            print_controller_state(&state);
-                            if let Some(handler) = &mut gamepad_handler {
-                                if let Err(e) = handler.update(&state) {
-                                    error!("Error updating gamepad: {}", e);
-                                }
-                            }
-                        }
-                        Err(_) => {}
+            Commands::Display { lines, line } => {
+                // Lines that run off the bottom of the screen would be lost
+                let room = (SCREEN_LINES - line.unwrap_or(1) + 1) as usize;
+                if lines.len() > room {
+                    anyhow::bail!("Only {} lines fit from line {}, got {}", room, line.unwrap_or(1), lines.len());
+                }
+                match run_display(&mut controller, &lines, line) {
+                    Err(e) if e.kind() == ErrorKind::Experimental => {
+                        anyhow::bail!("{}; pass --experimental to send it anyway", e)
                     }
-                    std::thread::sleep(Duration::from_millis(20));
+                    result => result.context("Failed to write to the screen")?,
                 }
             }
+            Commands::Pair { action } => match action {
+                PairAction::Show => {
+                    let id = controller.get_pair_id().context("Failed to get pair ID")?;
+                    println!("Pair ID: {}", id);
+                }
+                PairAction::Set { id } => set_pair_id(&mut controller, id).context("Failed to set pair ID")?,
+                PairAction::Reset => set_pair_id(&mut controller, FACTORY_PAIR_ID).context("Failed to reset pair ID")?,
+            },
         }
         return Ok(());
     }
@@ -149,59 +397,434 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-fn print_controller_state(state: &ControllerState) {
-    print!("\rLX: {:3} LY: {:3} RX: {:3} RY: {:3} | L: {}{} R: {}{} E: {}{} F: {}{} | L3: {} R3: {} | Bat: {:3}%   ",
-        state.left_x, state.left_y, state.right_x, state.right_y,
-        if state.l_up { "^" } else { " " }, if state.l_down { "v" } else { " " },
-        if state.r_up { "^" } else { " " }, if state.r_down { "v" } else { " " },
-        if state.e_up { "^" } else { " " }, if state.e_down { "v" } else { " " },
-        if state.f_up { "^" } else { " " }, if state.f_down { "v" } else { " " },
-        if state.l3 { "X" } else { " " }, if state.r3 { "X" } else { " " },
-        state.battery
+fn run_ping(controller: &mut VexController, count: u32, interval: Duration) -> Result<()> {
+    let before = controller.stats();
+    let mut rtts = Vec::new();
+    let (mut timeouts, mut crc_errors, mut other_errors) = (0u32, 0u32, 0u32);
+
+    for seq in 0..count {
+        match controller.ping() {
+            Ok(rtt) => {
+                println!("seq={} time={:.2} ms", seq, as_millis(rtt));
+                rtts.push(rtt);
+            }
+            Err(e) => {
+                println!("seq={} failed: {}", seq, e);
+                match e.kind() {
+                    ErrorKind::Timeout => timeouts += 1,
+                    ErrorKind::Crc => crc_errors += 1,
+                    _ => other_errors += 1,
+                }
+            }
+        }
+        if seq + 1 < count {
+            std::thread::sleep(interval);
+        }
+    }
+
+    println!();
+    println!("{} sent, {} received, {} timeouts, {} CRC errors, {} other errors",
+        count, rtts.len(), timeouts, crc_errors, other_errors
     );
-    io::stdout().flush().unwrap();
+
+    // The link counters also see attempts that a retry recovered from, and
+    // every corrupted frame rather than every failed ping
+    let after = controller.stats();
+    println!("link: {} timed out attempts, {} corrupted frames, {} mismatched replies",
+        after.timeouts - before.timeouts, after.crc_errors - before.crc_errors, after.mismatches - before.mismatches
+    );
+
+    if !rtts.is_empty() {
+        rtts.sort();
+        let avg = rtts.iter().sum::<Duration>() / rtts.len() as u32;
+        let p99 = rtts[(rtts.len() * 99).div_ceil(100) - 1];
+        println!("rtt min/avg/max/p99 = {:.2}/{:.2}/{:.2}/{:.2} ms",
+            as_millis(rtts[0]), as_millis(avg), as_millis(rtts[rtts.len() - 1]), as_millis(p99)
+        );
+    }
+
+    let failed = count as usize - rtts.len();
+    if failed > 0 {
+        anyhow::bail!("{} of {} pings failed", failed, count);
+    }
+    Ok(())
 }
 
-fn run_calibration(controller: &mut VexController) -> Result<()> {
-    // Send StartJsCal
-    match controller.start_calibration() {
-        Ok(_) => info!("Calibration command sent"),
-        Err(e) => tracing::warn!("Calibration command warning: {}", e),
+fn as_millis(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+const TEST_AXES: [&str; 4] = ["Left stick X", "Left stick Y", "Right stick X", "Right stick Y"];
+
+/// How far from mid-scale a stick at rest may read
+const CENTRE_TOLERANCE: u16 = 300;
+/// How close to either end of the ADC range a stick has to get
+const RANGE_MARGIN: u16 = 400;
+
+struct CheckResult {
+    name: String,
+    passed: bool,
+    detail: String,
+}
+
+fn run_selftest(controller: &mut VexController, timeout: Duration) -> Result<()> {
+    controller.set_test_mode(true)?;
+    let results = selftest_checks(controller, timeout);
+
+    // Leave test mode even if a check errored out
+    if let Err(e) = controller.set_test_mode(false) {
+        error!("Failed to leave test mode: {}", e);
     }
-    
-    println!("Calibration started.");
-    println!("Please rotate BOTH joysticks 360 degrees.");
+    let results = results?;
 
-    let mut last_cal_active = false;
-    let mut waiting_for_confirm = false;
+    println!("\nSelf-test report (experimental, the test data layout is unverified):");
+    for result in &results {
+        println!("  [{}] {:<20} {}", if result.passed { "PASS" } else { "FAIL" }, result.name, result.detail);
+    }
 
-    loop {
-        let state = controller.get_state()?;
-        
-        // Refresh status line
-        if !waiting_for_confirm {
-            print!("\rLeft: [{}] Right: [{}]   ", 
-                if state.cal_left { "DONE" } else { "    " },
-                if state.cal_right { "DONE" } else { "    " }
-            );
-            io::stdout().flush()?;
+    let failed = results.iter().filter(|r| !r.passed).count();
+    if failed > 0 {
+        anyhow::bail!("{} of {} checks failed", failed, results.len());
+    }
+    println!("All {} checks passed.", results.len());
+    Ok(())
+}
+
+fn selftest_checks(controller: &mut VexController, timeout: Duration) -> Result<Vec<CheckResult>> {
+    let mut results = Vec::new();
+
+    println!("Leave both sticks centred and release all buttons.");
+    std::thread::sleep(Duration::from_secs(1));
+    let rest = controller.get_test_data()?;
+    let mid = TestData::ADC_MAX / 2;
+    for (i, name) in TEST_AXES.iter().enumerate() {
+        results.push(CheckResult {
+            name: format!("{} centre", name),
+            passed: rest.sticks[i].abs_diff(mid) <= CENTRE_TOLERANCE,
+            detail: format!("raw {}", rest.sticks[i]),
+        });
+    }
+    results.push(CheckResult {
+        name: "Buttons released".to_string(),
+        passed: rest.buttons.is_empty(),
+        detail: format!("matrix {:#06X}", rest.buttons.bits()),
+    });
+
+    for &button in controller.model().buttons() {
+        print!("Press {}... ", button);
+        io::stdout().flush()?;
+
+        let (passed, detail) = match wait_for_test_data(controller, timeout, |data| data.buttons.pressed(button))? {
+            Some(data) => {
+                // Wait for release so the next prompt starts clean
+                let released = wait_for_test_data(controller, timeout, |data| !data.buttons.pressed(button))?.is_some();
+                let others = data.buttons.bits() & !button.bit();
+                if others != 0 {
+                    (false, format!("other bits set: {:#06X}", others))
+                } else if !released {
+                    (false, "not released".to_string())
+                } else {
+                    (true, "ok".to_string())
+                }
+            }
+            None => (false, "not detected".to_string()),
+        };
+        println!("{}", detail);
+        results.push(CheckResult { name: button.to_string(), passed, detail });
+    }
+
+    for (i, name) in TEST_AXES.iter().enumerate() {
+        print!("Move {} to both ends... ", name);
+        io::stdout().flush()?;
+
+        let (mut min, mut max) = (u16::MAX, 0);
+        let passed = wait_for_test_data(controller, timeout, |data| {
+            min = min.min(data.sticks[i]);
+            max = max.max(data.sticks[i]);
+            min <= RANGE_MARGIN && max >= TestData::ADC_MAX - RANGE_MARGIN
+        })?
+        .is_some();
+
+        let detail = format!("range {}..{}", min, max);
+        println!("{}", if passed { "ok" } else { "incomplete" });
+        results.push(CheckResult { name: name.to_string(), passed, detail });
+    }
+
+    Ok(results)
+}
+
+/// Poll test data until `done` accepts a reading, or give up after `timeout`.
+fn wait_for_test_data(
+    controller: &mut VexController,
+    timeout: Duration,
+    mut done: impl FnMut(&TestData) -> bool,
+) -> Result<Option<TestData>> {
+    let start = Instant::now();
+    while start.elapsed() < timeout {
+        let data = controller.get_test_data()?;
+        if done(&data) {
+            return Ok(Some(data));
         }
+        std::thread::sleep(Duration::from_millis(20));
+    }
+    Ok(None)
+}
 
-        if state.cal_left && state.cal_right {
-            if !waiting_for_confirm {
-                println!("\nBoth joysticks calibrated. Press 'E Up' button to confirm.");
-                waiting_for_confirm = true;
+fn run_update(controller: &mut VexController, file: &Path, resume_from: usize, reboot: bool) -> Result<()> {
+    let image = std::fs::read(file).with_context(|| format!("Failed to read {}", file.display()))?;
+    let options = UpdateOptions {
+        name: file.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+        resume_from,
+        reboot,
+        ..Default::default()
+    };
+
+    // Ctrl-C aborts the transfer on the controller instead of leaving it open
+    let cancelled = Arc::new(AtomicBool::new(false));
+    {
+        let cancelled = cancelled.clone();
+        tokio::spawn(async move {
+            if tokio::signal::ctrl_c().await.is_ok() {
+                cancelled.store(true, Ordering::Relaxed);
             }
+        });
+    }
+
+    println!("Uploading {} ({} bytes)...", file.display(), image.len());
+    let result = firmware::upload_firmware(controller, &image, &options, |progress| {
+        print!("\rUploading: {:5.1}% ({}/{} bytes)   ", progress.percent(), progress.written, progress.total);
+        io::stdout().flush().unwrap();
+        if cancelled.load(Ordering::Relaxed) {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
         }
+    });
+    println!();
 
-        // Check if calibration finished (active goes false)
-        if last_cal_active && !state.cal_active {
-             println!("\nCalibration complete!");
-             return Ok(());
+    if let Err(e) = &result {
+        if let Some(interrupted) = e.downcast_ref::<UploadInterrupted>() {
+            println!("Rerun with --resume-from {} to continue.", interrupted.offset);
         }
-        
-        last_cal_active = state.cal_active;
+    } else {
+        // An ACK to FileExit is all there is to go on, nothing reports the image as installed
+        println!("Sent {} bytes, all acknowledged; the controller accepted the image's CRC32.", image.len());
+    }
+    result
+}
+
+fn set_pair_id(controller: &mut VexController, id: u8) -> Result<()> {
+    info!("Setting pair ID to {}...", id);
+    controller.set_pair_id(id)?;
+
+    // Read it back, the controller doesn't report whether the write stuck
+    let confirmed = controller.get_pair_id()?;
+    if confirmed != id {
+        anyhow::bail!("Controller reports pair ID {} after setting {}", confirmed, id);
+    }
+
+    println!("Pair ID: {}", confirmed);
+    Ok(())
+}
+
+/// Link settings from the config file, overridden by any given on the command line.
+fn link_config(args: &Args, config: &Config) -> LinkConfig {
+    let mut link = config.link.clone();
+    if let Some(timeout) = args.timeout {
+        link.timeout_ms = timeout;
+    }
+    if let Some(timeout) = args.transfer_timeout {
+        link.transfer_timeout_ms = timeout;
+    }
+    if let Some(retries) = args.retries {
+        link.retries = retries;
+    }
+    if let Some(backoff) = args.backoff {
+        link.backoff_ms = backoff;
+    }
+    if let Some(delay) = args.read_delay {
+        link.read_delay_ms = delay;
+    }
+    link
+}
+
+fn list_devices() -> Result<()> {
+    let ports = serial::list_devices()?;
+    if ports.is_empty() {
+        println!("No VEX devices found");
+        return Ok(());
+    }
+
+    println!("{:<26} {:<16} {:<8} Port", "Device", "Serial", "Role");
+    for port in ports {
+        let serial = port.serial_number.as_deref().unwrap_or("-");
+        println!("{:<26} {:<16} {:<8} {}", port.family.to_string(), serial, port.role.to_string(), port.name);
+    }
+    Ok(())
+}
+
+/// The model given on the command line, or else the one detected from USB.
+fn controller_model(model: Option<ControllerModel>, port: &ControllerPort) -> ControllerModel {
+    model.or(port.model).unwrap_or_default()
+}
 
+fn port_selector(args: &Args, config: &Config) -> PortSelector {
+    if let Some(port) = &args.port {
+        PortSelector::Port(port.clone())
+    } else if let Some(serial) = &args.serial {
+        PortSelector::Serial(serial.clone())
+    } else if let Some(port) = &config.port {
+        PortSelector::Port(port.clone())
+    } else if let Some(serial) = &config.serial {
+        PortSelector::Serial(serial.clone())
+    } else {
+        PortSelector::Auto
+    }
+}
+
+/// How to find the controller at `port` again. A port given by name stays
+/// that port; otherwise the controller is picked by its serial number.
+fn reconnect_selector(selector: &PortSelector, port: &ControllerPort) -> PortSelector {
+    match (selector, &port.serial_number) {
+        (PortSelector::Port(_), _) | (_, None) => PortSelector::Port(port.name.clone()),
+        (_, Some(serial)) => PortSelector::Serial(serial.clone()),
+    }
+}
+
+/// The controller's identity, when its port has a USB serial number and it
+/// answers with its pair ID.
+fn device_id(port: &ControllerPort, controller: &mut VexController) -> Option<DeviceId> {
+    let serial_number = port.serial_number.clone()?;
+    match controller.get_pair_id() {
+        Ok(pair_id) => Some(DeviceId { serial_number, pair_id }),
+        Err(e) => {
+            warn!("Failed to get pair ID, device settings won't apply: {}", e);
+            None
+        }
+    }
+}
+
+/// Re-reads the device state now and then and reports when it changes.
+struct DeviceStateWatch {
+    last: Option<DeviceState>,
+    next_poll: Instant,
+}
+
+impl DeviceStateWatch {
+    const INTERVAL: Duration = Duration::from_secs(1);
+
+    fn new() -> Self {
+        Self { last: None, next_poll: Instant::now() }
+    }
+
+    fn poll(&mut self, controller: &mut VexController) -> Option<DeviceState> {
+        if Instant::now() < self.next_poll {
+            return None;
+        }
+        self.next_poll = Instant::now() + Self::INTERVAL;
+
+        let state = controller.get_device_state().ok()?;
+        if self.last == Some(state) {
+            return None;
+        }
+        self.last = Some(state);
+        Some(state)
+    }
+}
+
+fn print_controller_state(state: &ControllerState) {
+    print!("\r{}   ", state);
+    io::stdout().flush().unwrap();
+}
+
+fn print_raw_state(raw: &RawState, model: ControllerModel) {
+    println!("Raw:     {}", raw);
+    let unknown: Vec<String> = raw
+        .unknown_bytes()
+        .iter()
+        .map(|(offset, value)| format!("[{}]={:02X}", offset, value))
+        .collect();
+    println!(
+        "Unknown: {}  status bits {:02X}  button bits {:02X}",
+        unknown.join(" "),
+        raw.unknown_status_bits(),
+        raw.unknown_button_bits(model)
+    );
+}
+
+/// Print the raw state each time it changes, with the changed bytes marked
+/// on the line below.
+fn monitor_raw_state(controller: &mut VexController) -> Result<()> {
+    let start = Instant::now();
+    let mut previous: Option<RawState> = None;
+    loop {
+        match controller.get_state() {
+            Ok(state) if previous != Some(state.raw) => {
+                println!("{:9.3}  {}", start.elapsed().as_secs_f64(), state.raw);
+                if let Some(previous) = previous {
+                    println!("{:9}  {}", "", change_markers(&state.raw.changed_from(&previous)));
+                }
+                previous = Some(state.raw);
+            }
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::Io => {
+                return Err(e).context("Lost connection to controller");
+            }
+            Err(e) => debug!("Failed to get status: {}", e),
+        }
         std::thread::sleep(Duration::from_millis(20));
     }
 }
+
+/// `^^` under each changed byte, lined up with the [`RawState`] display.
+fn change_markers(changed: &[usize]) -> String {
+    let mut line = String::new();
+    for &offset in changed {
+        let groups = RawState::GROUP_STARTS.iter().filter(|&&start| start <= offset).count();
+        let column = offset * 3 + groups;
+        line.extend(std::iter::repeat_n(' ', column - line.len()));
+        line.push_str("^^");
+    }
+    line
+}
+
+fn run_display(controller: &mut VexController, lines: &[String], start: Option<u8>) -> Result<(), ProtocolError> {
+    let count = match start {
+        Some(_) => lines.len(),
+        None => SCREEN_LINES as usize,
+    };
+    let start = start.unwrap_or(1) - 1;
+    for i in 0..count.min((SCREEN_LINES - start) as usize) {
+        let text = lines.get(i).map(String::as_str).unwrap_or("");
+        controller.set_screen_line(start + i as u8, text)?;
+    }
+    Ok(())
+}
+
+fn run_calibration(controller: &mut VexController) -> Result<()> {
+    println!("Calibration started.");
+    println!("Please rotate BOTH joysticks 360 degrees.");
+
+    let mut waiting_for_confirm = false;
+    let outcome = calibration::run_calibration(controller, |state| {
+        if waiting_for_confirm {
+            return;
+        }
+        print!(
+            "\rLeft: [{}] Right: [{}]   ",
+            if state.cal_left { "DONE" } else { "    " },
+            if state.cal_right { "DONE" } else { "    " }
+        );
+        let _ = io::stdout().flush();
+        if state.cal_left && state.cal_right {
+            println!("\nBoth joysticks calibrated. Press 'E Up' button to confirm.");
+            waiting_for_confirm = true;
+        }
+    })?;
+
+    match outcome {
+        CalibrationOutcome::Complete => println!("\nCalibration complete!"),
+        CalibrationOutcome::Aborted => println!("\nCalibration aborted."),
+    }
+    Ok(())
+}
@@ -0,0 +1,282 @@
+use crate::protocol::{Button, Buttons, ProductType};
+use std::fmt;
+use std::str::FromStr;
+
+/// USB vendor ID of VEX Robotics
+pub const VEX_VID: u16 = 0x2888;
+
+/// A kind of VEX USB device, told apart by its product ID under [`VEX_VID`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum DeviceFamily {
+    IqGen1Brain,
+    IqGen2Brain,
+    IqGen2Controller,
+    V5Brain,
+    V5Controller,
+    Unknown(u16),
+}
+
+impl DeviceFamily {
+    pub fn from_pid(pid: u16) -> Self {
+        match pid {
+            // Not yet confirmed on hardware
+            0x0002 => Self::IqGen1Brain,
+            0x0200 => Self::IqGen2Brain,
+            0x0210 => Self::IqGen2Controller,
+            0x0501 => Self::V5Brain,
+            0x0503 => Self::V5Controller,
+            other => Self::Unknown(other),
+        }
+    }
+
+    /// The controller model, for the families this driver can talk to.
+    pub fn controller_model(self) -> Option<ControllerModel> {
+        match self {
+            Self::IqGen2Controller => Some(ControllerModel::IqGen2),
+            Self::V5Controller => Some(ControllerModel::V5),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for DeviceFamily {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IqGen1Brain => write!(f, "VEX IQ Gen 1 Brain"),
+            Self::IqGen2Brain => write!(f, "VEX IQ Gen 2 Brain"),
+            Self::IqGen2Controller => write!(f, "VEX IQ Gen 2 Controller"),
+            Self::V5Brain => write!(f, "VEX V5 Brain"),
+            Self::V5Controller => write!(f, "VEX V5 Controller"),
+            Self::Unknown(pid) => write!(f, "Unknown VEX device (PID {:#06X})", pid),
+        }
+    }
+}
+
+/// Identifies one controller however it is plugged in: its USB serial number
+/// and the pair ID it is set to.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DeviceId {
+    pub serial_number: String,
+    pub pair_id: u8,
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (pair ID {})", self.serial_number, self.pair_id)
+    }
+}
+
+/// A kind of controller this driver talks to. Both speak the same protocol
+/// and `GetState` frame, but have different buttons.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ControllerModel {
+    #[default]
+    IqGen2,
+    V5,
+}
+
+impl ControllerModel {
+    pub const ALL: [ControllerModel; 2] = [Self::IqGen2, Self::V5];
+
+    /// The model with this USB product ID under [`VEX_VID`].
+    pub fn from_pid(pid: u16) -> Option<Self> {
+        DeviceFamily::from_pid(pid).controller_model()
+    }
+
+    pub fn family(self) -> DeviceFamily {
+        match self {
+            Self::IqGen2 => DeviceFamily::IqGen2Controller,
+            Self::V5 => DeviceFamily::V5Controller,
+        }
+    }
+
+    pub fn product_type(self) -> ProductType {
+        match self {
+            Self::IqGen2 => ProductType::IqGen2Controller,
+            Self::V5 => ProductType::V5Controller,
+        }
+    }
+
+    /// Short name used on the command line, `iq2` or `v5`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::IqGen2 => "iq2",
+            Self::V5 => "v5",
+        }
+    }
+
+    pub fn has_rumble(self) -> bool {
+        self == Self::V5
+    }
+
+    pub fn has_screen(self) -> bool {
+        self == Self::V5
+    }
+
+    /// The model's buttons, in display order.
+    pub fn buttons(self) -> &'static [Button] {
+        match self {
+            Self::IqGen2 => &IQ_GEN2_BUTTONS,
+            Self::V5 => &V5_BUTTONS,
+        }
+    }
+
+    /// Buttons by their bit in the `GetState` button word, which is byte 9
+    /// with byte 10 above it.
+    fn wire_buttons(self) -> &'static [Button] {
+        match self {
+            Self::IqGen2 => &IQ_GEN2_WIRE,
+            Self::V5 => &V5_WIRE,
+        }
+    }
+
+    pub fn decode_buttons(self, word: u16) -> Buttons {
+        self.wire_buttons()
+            .iter()
+            .enumerate()
+            .filter(|&(bit, _)| word & (1 << bit) != 0)
+            .map(|(_, &button)| button)
+            .collect()
+    }
+
+    pub fn encode_buttons(self, buttons: Buttons) -> u16 {
+        self.wire_buttons()
+            .iter()
+            .enumerate()
+            .filter(|&(_, &button)| buttons.pressed(button))
+            .fold(0, |word, (bit, _)| word | 1 << bit)
+    }
+
+    /// Bits of `GetState` byte 10 that hold buttons.
+    pub fn high_button_mask(self) -> u8 {
+        self.wire_buttons().iter().enumerate().skip(8).fold(0, |mask, (bit, _)| mask | 1 << (bit - 8))
+    }
+}
+
+const IQ_GEN2_BUTTONS: [Button; 10] = [
+    Button::LUp,
+    Button::LDown,
+    Button::RUp,
+    Button::RDown,
+    Button::EUp,
+    Button::EDown,
+    Button::FUp,
+    Button::FDown,
+    Button::L3,
+    Button::R3,
+];
+
+const IQ_GEN2_WIRE: [Button; 10] = [
+    Button::FDown,
+    Button::EDown,
+    Button::FUp,
+    Button::EUp,
+    Button::LDown,
+    Button::LUp,
+    Button::RDown,
+    Button::RUp,
+    Button::L3,
+    Button::R3,
+];
+
+const V5_BUTTONS: [Button; 12] = [
+    Button::L1,
+    Button::L2,
+    Button::R1,
+    Button::R2,
+    Button::Up,
+    Button::Down,
+    Button::Left,
+    Button::Right,
+    Button::X,
+    Button::B,
+    Button::Y,
+    Button::A,
+];
+
+/// The V5 bit order is provisional; check it against hardware with `status --raw`.
+const V5_WIRE: [Button; 12] = V5_BUTTONS;
+
+impl fmt::Display for ControllerModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.family().fmt(f)
+    }
+}
+
+/// Accepts [`ControllerModel::name`] in any case.
+impl FromStr for ControllerModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_ascii_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|model| model.name() == name)
+            .ok_or_else(|| format!("Unknown controller model '{}', expected iq2 or v5", s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The button each bit of the `GetState` word decodes to
+    fn decoded_bits(model: ControllerModel) -> Vec<Vec<Button>> {
+        (0..16).map(|bit| model.decode_buttons(1 << bit).iter().collect()).collect()
+    }
+
+    #[test]
+    fn v5_wire_order() {
+        let expected = [
+            Button::L1,
+            Button::L2,
+            Button::R1,
+            Button::R2,
+            Button::Up,
+            Button::Down,
+            Button::Left,
+            Button::Right,
+            Button::X,
+            Button::B,
+            Button::Y,
+            Button::A,
+        ];
+        let bits = decoded_bits(ControllerModel::V5);
+        for (bit, button) in expected.into_iter().enumerate() {
+            assert_eq!(bits[bit], [button], "bit {}", bit);
+            assert_eq!(ControllerModel::V5.encode_buttons([button].into_iter().collect()), 1 << bit, "{}", button);
+        }
+        assert!(bits[12..].iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn iq_gen2_wire_order() {
+        let bits = decoded_bits(ControllerModel::IqGen2);
+        assert_eq!(bits[0], [Button::FDown]);
+        assert_eq!(bits[3], [Button::EUp]);
+        assert_eq!(bits[5], [Button::LUp]);
+        assert_eq!(bits[9], [Button::R3]);
+        assert!(bits[10..].iter().all(Vec::is_empty));
+    }
+
+    #[test]
+    fn buttons_round_trip_through_the_wire() {
+        for model in ControllerModel::ALL {
+            let all: Buttons = model.buttons().iter().copied().collect();
+            assert_eq!(model.decode_buttons(model.encode_buttons(all)), all, "{}", model);
+            // Buttons of the other model aren't sent
+            let others: Buttons = Button::ALL.into_iter().filter(|button| !model.buttons().contains(button)).collect();
+            assert_eq!(model.encode_buttons(others), 0, "{}", model);
+        }
+    }
+
+    #[test]
+    fn high_button_mask_covers_the_buttons_in_byte_10() {
+        assert_eq!(ControllerModel::IqGen2.high_button_mask(), 0b0000_0011);
+        assert_eq!(ControllerModel::V5.high_button_mask(), 0b0000_1111);
+        for model in ControllerModel::ALL {
+            let high = (model.encode_buttons(model.buttons().iter().copied().collect()) >> 8) as u8;
+            assert_eq!(high, model.high_button_mask(), "{}", model);
+        }
+    }
+}
@@ -1,26 +1,64 @@
+use crate::error::{ProtocolError, ResponseMismatch, RetryAction, RetryPolicy};
+use crate::event::Events;
+use crate::frame::{FrameDecoder, FrameError};
 use crate::transport::Transport;
-use anyhow::{Result, bail};
-use byteorder::{BigEndian, ByteOrder};
+pub use crate::state::{Axis, Button, Buttons, ControllerState, RawState};
+use crate::model::ControllerModel;
+use byteorder::{ByteOrder, LittleEndian};
 use crc::{Crc, CRC_16_XMODEM, CRC_32_ISO_HDLC};
+use serde::Deserialize;
+use std::cmp::Ordering;
+use std::fmt;
+use std::str::FromStr;
 use std::time::{Duration, Instant};
 use tracing::debug;
 
 pub const HEADERS: [u8; 4] = [0xC9, 0x36, 0xB8, 0x47];
 pub const HEADERR: [u8; 2] = [0xAA, 0x55];
 
+/// Acknowledgement byte that follows the command byte in system command replies
+pub const ACK: u8 = 0x76;
+
 pub const CRC16_XMODEM: Crc<u16> = Crc::<u16>::new(&CRC_16_XMODEM);
-#[allow(dead_code)]
 pub const CRC32: Crc<u32> = Crc::<u32>::new(&CRC_32_ISO_HDLC);
 
+/// First command byte, or the second one for commands sent through `SystemCdc`.
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy)]
 pub enum Command {
     SysStatus = 0x20,
     FileInit = 0x11,
+    FileExit = 0x12,
+    FileWrite = 0x13,
     FactoryPing = 0xF4,
+    SystemCdc = 0x56,
     ControllerCdc = 0x58,
 }
 
+impl TryFrom<u8> for Command {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x20 => Ok(Self::SysStatus),
+            0x11 => Ok(Self::FileInit),
+            0x12 => Ok(Self::FileExit),
+            0x13 => Ok(Self::FileWrite),
+            0xF4 => Ok(Self::FactoryPing),
+            0x56 => Ok(Self::SystemCdc),
+            0x58 => Ok(Self::ControllerCdc),
+            other => Err(other),
+        }
+    }
+}
+
+impl Command {
+    /// File transfer commands, which can take much longer to answer
+    pub fn is_file_transfer(&self) -> bool {
+        matches!(self, Self::FileInit | Self::FileWrite | Self::FileExit)
+    }
+}
+
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy)]
 pub enum ControllerSubCommand {
@@ -35,36 +73,366 @@ pub enum ControllerSubCommand {
     DevState = 0x68,
 }
 
+/// V5 rumble and screen text subcommands. No source documents these values;
+/// they are guesses following on from the known subcommands, so they stay out
+/// of [`ControllerSubCommand`] and are only sent once experimental commands
+/// are enabled.
+pub(crate) const RUMBLE_SUBCOMMAND: u8 = 0x69;
+pub(crate) const SCREEN_TEXT_SUBCOMMAND: u8 = 0x6A;
+
+impl TryFrom<u8> for ControllerSubCommand {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0x60 => Ok(Self::GetState),
+            0x61 => Ok(Self::SetPairId),
+            0x62 => Ok(Self::GetPairId),
+            0x63 => Ok(Self::GetTestData),
+            0x64 => Ok(Self::TestCmd),
+            0x65 => Ok(Self::AbortJsCal),
+            0x66 => Ok(Self::StartJsCal),
+            0x67 => Ok(Self::GetVersions),
+            0x68 => Ok(Self::DevState),
+            other => Err(other),
+        }
+    }
+}
+
 pub fn calculate_crc16(data: &[u8]) -> u16 {
     CRC16_XMODEM.checksum(data)
 }
 
-#[derive(Debug, Default, Clone, Copy)]
-pub struct ControllerState {
-    pub left_x: u8,
-    pub left_y: u8,
-    pub right_x: u8,
-    pub right_y: u8,
-    pub l_up: bool,
-    pub l_down: bool,
-    pub r_up: bool,
-    pub r_down: bool,
-    pub e_up: bool,
-    pub e_down: bool,
-    pub f_up: bool,
-    pub f_down: bool,
-    pub l3: bool,
-    pub r3: bool,
-    pub battery: u8,
-    pub cal_active: bool,
-    pub cal_left: bool,
-    pub cal_right: bool,
-}
-
-struct Protocol;
+/// A firmware version as major.minor.build with an optional beta number.
+///
+/// Versions order by major, minor and build, and a beta sorts before the
+/// release it leads up to: `1.0.4b1 < 1.0.4b2 < 1.0.4`. Parses from the
+/// displayed form, so it can be written as a string in config files.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct Version {
+    pub major: u8,
+    pub minor: u8,
+    pub build: u8,
+    pub beta: u8,
+}
+
+impl Version {
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes {
+            [major, minor, build, beta, ..] => Some(Self { major: *major, minor: *minor, build: *build, beta: *beta }),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; 4] {
+        [self.major, self.minor, self.build, self.beta]
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.build)?;
+        if self.beta != 0 {
+            write!(f, "b{}", self.beta)?;
+        }
+        Ok(())
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // A beta of 0 is the release, which comes after every beta
+        let beta = |v: &Version| if v.beta == 0 { u16::MAX } else { v.beta as u16 };
+        (self.major, self.minor, self.build, beta(self)).cmp(&(other.major, other.minor, other.build, beta(other)))
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// Accepts `major.minor[.build][bN]`, such as `1.0`, `1.0.4` or `1.0.4b2`.
+impl FromStr for Version {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid version '{}', expected major.minor.build[bN]", s);
+        let (release, beta) = match s.trim().split_once(['b', 'B']) {
+            Some((release, beta)) => (release, beta.parse().ok().filter(|&beta| beta != 0).ok_or_else(invalid)?),
+            None => (s.trim(), 0),
+        };
+        let parts: Vec<u8> = release.split('.').map(|part| part.parse().map_err(|_| invalid())).collect::<Result<_, _>>()?;
+        match parts[..] {
+            [major, minor] => Ok(Self { major, minor, build: 0, beta }),
+            [major, minor, build] => Ok(Self { major, minor, build, beta }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl TryFrom<String> for Version {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// Decoded `GetVersions` reply.
+///
+/// The controller answers with text. Every word in it that reads as a dotted
+/// [`Version`] is parsed, in the order it appears; the text itself is always
+/// kept, so nothing is lost when no part of it parses.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct ControllerVersions {
+    /// The reply as sent, decoded lossily as UTF-8
+    pub text: String,
+    /// Versions found in `text`
+    pub versions: Vec<Version>,
+}
+
+impl ControllerVersions {
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        match payload {
+            [_, text @ ..] if !text.is_empty() => Some(Self::from_text(&String::from_utf8_lossy(text))),
+            _ => None,
+        }
+    }
+
+    pub fn from_text(text: &str) -> Self {
+        let versions = text
+            .split(|c: char| c.is_whitespace() || matches!(c, ',' | ';' | '(' | ')'))
+            .filter_map(|word| word.strip_prefix(['v', 'V']).unwrap_or(word).parse().ok())
+            .collect();
+        Self { text: text.to_string(), versions }
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = vec![ControllerSubCommand::GetVersions as u8];
+        payload.extend_from_slice(self.text.as_bytes());
+        payload
+    }
+
+    /// The first version in the reply, taken to be the main firmware.
+    pub fn main(&self) -> Option<Version> {
+        self.versions.first().copied()
+    }
+}
+
+impl fmt::Display for ControllerVersions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+/// Declares an enum read from a single byte: an `Unknown(u8)` variant for
+/// values not listed, `u8` conversions both ways, and `Display` from the
+/// label given for each variant.
+macro_rules! byte_enum {
+    (
+        $(#[$meta:meta])*
+        pub enum $name:ident {
+            $($(#[$variant_meta:meta])* $variant:ident = $value:literal => $label:literal,)+
+        }
+    ) => {
+        $(#[$meta])*
+        pub enum $name {
+            $($(#[$variant_meta])* $variant,)+
+            Unknown(u8),
+        }
+
+        impl From<u8> for $name {
+            fn from(value: u8) -> Self {
+                match value {
+                    $($value => Self::$variant,)+
+                    other => Self::Unknown(other),
+                }
+            }
+        }
+
+        impl From<$name> for u8 {
+            fn from(value: $name) -> Self {
+                match value {
+                    $($name::$variant => $value,)+
+                    $name::Unknown(other) => other,
+                }
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match self {
+                    $(Self::$variant => write!(f, $label),)+
+                    Self::Unknown(other) => write!(f, "Unknown ({:#04X})", other),
+                }
+            }
+        }
+    };
+}
+
+// The SysStatus, DevState and GetTestData layouts below are guesses that have
+// only been checked against the simulator. Anything decoded from them is
+// experimental and is labelled as such wherever it is shown.
+
+byte_enum! {
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub enum ProductType {
+        V5Brain = 0x10 => "V5 Brain",
+        V5Controller = 0x11 => "V5 Controller",
+        IqGen2Brain = 0x20 => "IQ Gen 2 Brain",
+        IqGen2Controller = 0x21 => "IQ Gen 2 Controller",
+    }
+}
+
+/// Decoded `SysStatus` reply: product byte, system and bootloader versions,
+/// then little-endian flags.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemStatus {
+    pub product_type: ProductType,
+    pub system_version: Version,
+    pub bootloader_version: Version,
+    pub flags: u32,
+    /// Bytes after the known fields, kept as-is
+    pub extra: Vec<u8>,
+}
+
+impl SystemStatus {
+    /// Length of the fields decoded so far
+    pub const LEN: usize = 13;
+
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < Self::LEN {
+            return None;
+        }
+
+        Some(Self {
+            product_type: ProductType::from(data[0]),
+            system_version: Version::from_bytes(&data[1..5])?,
+            bootloader_version: Version::from_bytes(&data[5..9])?,
+            flags: LittleEndian::read_u32(&data[9..13]),
+            extra: data[Self::LEN..].to_vec(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(Self::LEN + self.extra.len());
+        data.push(self.product_type.into());
+        data.extend_from_slice(&self.system_version.to_bytes());
+        data.extend_from_slice(&self.bootloader_version.to_bytes());
+        data.extend_from_slice(&self.flags.to_le_bytes());
+        data.extend_from_slice(&self.extra);
+        data
+    }
+}
+
+byte_enum! {
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub enum RadioLink {
+        #[default]
+        Disconnected = 0 => "Disconnected",
+        Searching = 1 => "Searching",
+        Connected = 2 => "Connected",
+    }
+}
+
+byte_enum! {
+    #[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+    pub enum ChargeState {
+        #[default]
+        NotCharging = 0 => "Not charging",
+        Charging = 1 => "Charging",
+        Charged = 2 => "Charged",
+    }
+}
+
+/// Decoded `DevState` reply: how the controller is linked to a brain and its
+/// charging status.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceState {
+    pub link: RadioLink,
+    /// Connected to the brain by cable rather than by radio
+    pub tethered: bool,
+    pub charging: ChargeState,
+}
+
+impl DeviceState {
+    pub fn parse(payload: &[u8]) -> Option<Self> {
+        if payload.len() < 4 || payload[0] != ControllerSubCommand::DevState as u8 {
+            return None;
+        }
+
+        Some(Self {
+            link: RadioLink::from(payload[1]),
+            tethered: payload[2] & 0x01 != 0,
+            charging: ChargeState::from(payload[3]),
+        })
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        vec![
+            ControllerSubCommand::DevState as u8,
+            self.link.into(),
+            self.tethered as u8,
+            self.charging.into(),
+        ]
+    }
+}
+
+impl fmt::Display for DeviceState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Link: {} ({}) | {} (experimental)",
+            self.link,
+            if self.tethered { "tethered" } else { "wireless" },
+            self.charging
+        )
+    }
+}
+
+/// Decoded `GetTestData` reply, only valid while the controller is in test mode:
+/// four little-endian 12-bit stick readings, then the button word.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TestData {
+    /// Raw ADC readings for left X, left Y, right X and right Y
+    pub sticks: [u16; 4],
+    /// Button matrix, laid out like the model's `GetState` button word
+    pub buttons: Buttons,
+    pub model: ControllerModel,
+}
+
+impl TestData {
+    /// Full-scale reading of the stick ADCs, assuming they are 12-bit
+    pub const ADC_MAX: u16 = 4095;
+
+    pub fn parse(model: ControllerModel, payload: &[u8]) -> Option<Self> {
+        if payload.len() < 11 || payload[0] != ControllerSubCommand::GetTestData as u8 {
+            return None;
+        }
+
+        let mut sticks = [0u16; 4];
+        LittleEndian::read_u16_into(&payload[1..9], &mut sticks);
+        Some(Self {
+            sticks,
+            buttons: model.decode_buttons(LittleEndian::read_u16(&payload[9..11])),
+            model,
+        })
+    }
+
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = vec![0u8; 11];
+        payload[0] = ControllerSubCommand::GetTestData as u8;
+        LittleEndian::write_u16_into(&self.sticks, &mut payload[1..9]);
+        LittleEndian::write_u16(&mut payload[9..11], self.model.encode_buttons(self.buttons));
+        payload
+    }
+}
+
+pub(crate) struct Protocol;
 
 impl Protocol {
-    fn encode_command(cmd1: u8, cmd2: u8, data: &[u8]) -> Vec<u8> {
+    pub(crate) fn encode_command(cmd1: u8, cmd2: u8, data: &[u8]) -> Vec<u8> {
         let mut packet = Vec::new();
         packet.extend_from_slice(&HEADERS);
         packet.push(cmd1);
@@ -87,183 +455,564 @@ impl Protocol {
         packet
     }
 
-    fn decode_response(buffer: &[u8]) -> Option<Vec<u8>> {
-        // Basic validation
-        if buffer.len() < 5 {
-            return None;
+    pub(crate) fn encode_response(cmd1: u8, payload: &[u8]) -> Vec<u8> {
+        let mut packet = Vec::new();
+        packet.extend_from_slice(&HEADERR);
+        packet.push(cmd1);
+
+        // Length covers the payload and the trailing CRC
+        let len = payload.len() + 2;
+        if len < 128 {
+            packet.push(len as u8);
+        } else {
+            packet.push(((len >> 8) as u8) | 0x80);
+            packet.push((len & 0xFF) as u8);
         }
+        packet.extend_from_slice(payload);
 
-        // Check header
-        if buffer[0..2] != HEADERR {
-            return None;
+        let crc = calculate_crc16(&packet);
+        packet.push((crc >> 8) as u8);
+        packet.push((crc & 0xFF) as u8);
+
+        packet
+    }
+}
+
+/// Counters for link problems seen by a `VexController`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LinkStats {
+    /// Frames dropped because their CRC didn't match
+    pub crc_errors: u64,
+    /// Commands that got no valid response in time
+    pub timeouts: u64,
+    /// Valid frames dropped because they answered a different command
+    pub mismatches: u64,
+}
+
+/// Picks the reply to one command out of decoded frames, remembering what was
+/// dropped on the way so a timeout can be reported accurately.
+pub(crate) struct ReplyMatcher {
+    cmd1: u8,
+    cmd2: u8,
+    mismatch: Option<ResponseMismatch>,
+    bad_crc: Option<FrameError>,
+}
+
+impl ReplyMatcher {
+    pub(crate) fn new(cmd1: u8, cmd2: u8) -> Self {
+        Self { cmd1, cmd2, mismatch: None, bad_crc: None }
+    }
+
+    /// Take frames out of `decoder` until the reply turns up.
+    pub(crate) fn next_reply(&mut self, decoder: &mut FrameDecoder, stats: &mut LinkStats) -> Option<Vec<u8>> {
+        while let Some(frame) = decoder.next_frame() {
+            match frame {
+                Ok(frame) if frame.command == self.cmd1 && frame.subcommand() == Some(self.cmd2) => {
+                    debug!("Raw response: {:02X?}", frame.payload);
+                    return Some(frame.payload);
+                }
+                Ok(frame) => {
+                    // A stale reply to something else, keep waiting for ours
+                    debug!("Dropped unrelated response: {:02X} {:02X?}", frame.command, frame.payload);
+                    stats.mismatches += 1;
+                    self.mismatch = Some(ResponseMismatch {
+                        expected: (self.cmd1, self.cmd2),
+                        received: (frame.command, frame.subcommand()),
+                    });
+                }
+                Err(e) => {
+                    debug!("Dropped frame: {}", e);
+                    if let FrameError::BadCrc { .. } = e {
+                        stats.crc_errors += 1;
+                        self.bad_crc = Some(e);
+                    }
+                }
+            }
         }
+        None
+    }
 
-        // Extract data
-        // Length parsing (at index 3)
-        let (len, header_size) = if (buffer[3] & 0x80) != 0 {
-            if buffer.len() < 5 { return None; }
-            let len = ((buffer[3] & 0x7F) as usize) << 8 | (buffer[4] as usize);
-            (len, 5)
-        } else {
-            (buffer[3] as usize, 4)
-        };
+    /// The error to report when the reply didn't arrive in time.
+    pub(crate) fn timed_out(self, stats: &mut LinkStats) -> ProtocolError {
+        // A corrupted frame may well have been our reply, so report that first
+        if let Some(e) = self.bad_crc {
+            return ProtocolError::Crc(e);
+        }
+        if let Some(mismatch) = self.mismatch {
+            return ProtocolError::Mismatch(mismatch);
+        }
+        stats.timeouts += 1;
+        ProtocolError::Timeout
+    }
+}
 
-        // Check if we have enough data
-        // len includes Payload + CRC
-        let packet_len = header_size + len;
-        if buffer.len() < packet_len {
-            return None;
+/// Check the echo and ACK of a system command reply and return the data after them.
+pub(crate) fn system_reply_data(cmd: Command, payload: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+    if payload.len() < 2 {
+        return Err(ProtocolError::Parse("system command reply"));
+    }
+    if payload[1] != ACK {
+        return Err(ProtocolError::Nack { command: cmd, code: payload[1] });
+    }
+    Ok(payload[2..].to_vec())
+}
+
+/// Pair ID `pair reset` writes back. It is what the simulator starts with;
+/// whether the firmware treats it as "not paired" is unconfirmed, so a reset
+/// only restores this known value.
+pub const FACTORY_PAIR_ID: u8 = 0;
+
+/// Lines on the V5 controller screen
+pub const SCREEN_LINES: u8 = 3;
+/// Characters per line on the V5 controller screen
+pub const SCREEN_COLUMNS: usize = 19;
+/// Longest rumble pattern the V5 controller plays
+pub const RUMBLE_PATTERN_MAX: usize = 8;
+
+/// Rumble data: the pattern in ASCII, `.` for a short pulse, `-` for a long
+/// one and ` ` for a pause. Other characters are dropped and the pattern is
+/// cut to [`RUMBLE_PATTERN_MAX`].
+fn rumble_data(model: ControllerModel, pattern: &str) -> Result<Vec<u8>, ProtocolError> {
+    if !model.has_rumble() {
+        return Err(ProtocolError::Unsupported { model, feature: "rumble motor" });
+    }
+    Ok(pattern.bytes().filter(|b| matches!(b, b'.' | b'-' | b' ')).take(RUMBLE_PATTERN_MAX).collect())
+}
+
+/// Screen text data: the line, then the text padded with spaces to the full
+/// width so it replaces whatever was there. Characters the screen can't show
+/// become `?`.
+fn screen_line_data(model: ControllerModel, line: u8, text: &str) -> Result<Vec<u8>, ProtocolError> {
+    if !model.has_screen() {
+        return Err(ProtocolError::Unsupported { model, feature: "screen" });
+    }
+    if line >= SCREEN_LINES {
+        return Err(ProtocolError::Unsupported { model, feature: "screen line past the third" });
+    }
+
+    let mut data = vec![line];
+    data.extend(text.chars().map(|c| if c.is_ascii() && !c.is_ascii_control() { c as u8 } else { b'?' }).take(SCREEN_COLUMNS));
+    data.resize(1 + SCREEN_COLUMNS, b' ');
+    Ok(data)
+}
+
+/// Where a [`Request`] is sent.
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Target {
+    /// A `ControllerCdc` subcommand
+    Controller(u8),
+    /// An extended system command, answered with an ACK before its data
+    System(Command),
+}
+
+/// A command and how to decode its reply. Built here and sent by both
+/// [`VexController`] and the async controller, which only differ in how they
+/// wait on the transport.
+pub(crate) struct Request<T> {
+    pub target: Target,
+    pub data: Vec<u8>,
+    /// Decodes the reply payload, or for system commands the data after the ACK
+    pub decode: fn(ControllerModel, &[u8]) -> Result<T, ProtocolError>,
+}
+
+impl Request<()> {
+    fn controller(subcommand: u8, data: Vec<u8>) -> Self {
+        Self { target: Target::Controller(subcommand), data, decode: |_, _| Ok(()) }
+    }
+
+    pub fn set_pair_id(pair_id: u8) -> Self {
+        Self::controller(ControllerSubCommand::SetPairId as u8, vec![pair_id])
+    }
+
+    pub fn start_calibration() -> Self {
+        Self::controller(ControllerSubCommand::StartJsCal as u8, Vec::new())
+    }
+
+    pub fn abort_calibration() -> Self {
+        Self::controller(ControllerSubCommand::AbortJsCal as u8, Vec::new())
+    }
+
+    pub fn set_test_mode(enabled: bool) -> Self {
+        Self::controller(ControllerSubCommand::TestCmd as u8, vec![enabled as u8])
+    }
+
+    pub fn rumble(settings: &VexControllerBuilder, pattern: &str) -> Result<Self, ProtocolError> {
+        settings.check_experimental("rumble")?;
+        Ok(Self::controller(RUMBLE_SUBCOMMAND, rumble_data(settings.model, pattern)?))
+    }
+
+    pub fn set_screen_line(settings: &VexControllerBuilder, line: u8, text: &str) -> Result<Self, ProtocolError> {
+        settings.check_experimental("screen text")?;
+        Ok(Self::controller(SCREEN_TEXT_SUBCOMMAND, screen_line_data(settings.model, line, text)?))
+    }
+
+    pub fn ping() -> Self {
+        Self { target: Target::System(Command::FactoryPing), data: Vec::new(), decode: |_, _| Ok(()) }
+    }
+}
+
+impl Request<ControllerState> {
+    pub fn get_state() -> Self {
+        Self {
+            target: Target::Controller(ControllerSubCommand::GetState as u8),
+            data: Vec::new(),
+            decode: |model, payload| ControllerState::parse(model, payload).ok_or(ProtocolError::Parse("state payload")),
         }
-        
-        let packet = &buffer[..packet_len];
+    }
+}
 
-        // Check CRC
-        let received_crc = BigEndian::read_u16(&packet[packet_len - 2..]);
-        let calculated_crc = calculate_crc16(&packet[..packet_len - 2]);
+impl Request<ControllerVersions> {
+    pub fn get_versions() -> Self {
+        Self {
+            target: Target::Controller(ControllerSubCommand::GetVersions as u8),
+            data: Vec::new(),
+            decode: |_, payload| ControllerVersions::parse(payload).ok_or(ProtocolError::Parse("version payload")),
+        }
+    }
+}
 
-        if received_crc != calculated_crc {
-            return None;
+impl Request<u8> {
+    pub fn get_pair_id() -> Self {
+        Self {
+            target: Target::Controller(ControllerSubCommand::GetPairId as u8),
+            data: Vec::new(),
+            decode: |_, payload| payload.get(1).copied().ok_or(ProtocolError::Parse("pair ID payload")),
         }
+    }
+}
 
-        // Return payload, excluding CRC
-        Some(packet[header_size..packet_len - 2].to_vec())
+impl Request<DeviceState> {
+    pub fn get_device_state() -> Self {
+        Self {
+            target: Target::Controller(ControllerSubCommand::DevState as u8),
+            data: Vec::new(),
+            decode: |_, payload| DeviceState::parse(payload).ok_or(ProtocolError::Parse("device state payload")),
+        }
     }
+}
 
-    fn parse_controller_state(payload: &[u8]) -> Option<ControllerState> {
-        if payload.len() < 14 || payload[0] != 0x60 {
-            return None;
+impl Request<TestData> {
+    pub fn get_test_data() -> Self {
+        Self {
+            target: Target::Controller(ControllerSubCommand::GetTestData as u8),
+            data: Vec::new(),
+            decode: |model, payload| TestData::parse(model, payload).ok_or(ProtocolError::Parse("test data payload")),
         }
+    }
+}
 
-        let left_x = payload[1];
-        let left_y = payload[2];
-        let right_x = payload[3];
-        let right_y = payload[4];
-        
-        let buttons = BigEndian::read_u16(&payload[8..10]);
-        let extra_buttons = payload[10];
-        
-        let status = payload[8];
-        
-        Some(ControllerState {
-            left_x,
-            left_y,
-            right_x,
-            right_y,
-            l_up: (buttons >> 5) & 1 != 0,
-            l_down: (buttons >> 4) & 1 != 0,
-            r_up: (buttons >> 7) & 1 != 0,
-            r_down: (buttons >> 6) & 1 != 0,
-            e_up: (buttons >> 3) & 1 != 0,
-            e_down: (buttons >> 1) & 1 != 0,
-            f_up: (buttons >> 2) & 1 != 0,
-            f_down: (buttons >> 0) & 1 != 0,
-            l3: (extra_buttons & 0x01) != 0,
-            r3: (extra_buttons & 0x02) != 0,
-            battery: payload[11], 
-            cal_active: (status >> 4) & 1 != 0,
-            cal_left: (status >> 5) & 1 != 0,
-            cal_right: (status >> 6) & 1 != 0,
-        })
+impl Request<SystemStatus> {
+    pub fn get_system_status() -> Self {
+        Self {
+            target: Target::System(Command::SysStatus),
+            data: Vec::new(),
+            decode: |_, data| SystemStatus::parse(data).ok_or(ProtocolError::Parse("system status payload")),
+        }
+    }
+}
+
+/// Default time to wait for a reply
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Default time to wait for a file transfer reply, which can involve erasing
+/// or verifying flash
+pub const DEFAULT_TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default delay before the first retry, doubled for each retry after it
+pub const DEFAULT_BACKOFF: Duration = Duration::from_millis(50);
+/// Default pause between reads while waiting for a reply
+pub const DEFAULT_READ_DELAY: Duration = Duration::from_millis(10);
+/// Default size of the buffer each read goes into
+pub const DEFAULT_BUFFER_SIZE: usize = 1024;
+
+/// Builds a `VexController` with custom timing and retry settings.
+#[derive(Debug, Clone)]
+pub struct VexControllerBuilder {
+    pub(crate) timeout: Duration,
+    pub(crate) transfer_timeout: Duration,
+    pub(crate) backoff: Duration,
+    pub(crate) read_delay: Duration,
+    pub(crate) buffer_size: usize,
+    pub(crate) retry_policy: RetryPolicy,
+    pub(crate) model: ControllerModel,
+    pub(crate) experimental: bool,
+}
+
+impl VexControllerBuilder {
+    pub fn new() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT,
+            transfer_timeout: DEFAULT_TRANSFER_TIMEOUT,
+            backoff: DEFAULT_BACKOFF,
+            read_delay: DEFAULT_READ_DELAY,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            retry_policy: RetryPolicy::default(),
+            model: ControllerModel::default(),
+            experimental: false,
+        }
+    }
+
+    /// How long to wait for the reply to each command.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// How long to wait for replies to `FileInit`, `FileWrite` and `FileExit`.
+    pub fn transfer_timeout(mut self, timeout: Duration) -> Self {
+        self.transfer_timeout = timeout;
+        self
+    }
+
+    /// How many times a failed command is retried, for the errors the retry
+    /// policy marks as retryable.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retry_policy = self.retry_policy.with_max_retries(retries);
+        self
+    }
+
+    /// Delay before the first retry. Each further retry waits twice as long.
+    pub fn backoff(mut self, backoff: Duration) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Pause between reads while waiting for a reply.
+    pub fn read_delay(mut self, delay: Duration) -> Self {
+        self.read_delay = delay;
+        self
+    }
+
+    pub fn buffer_size(mut self, size: usize) -> Self {
+        self.buffer_size = size.max(1);
+        self
+    }
+
+    /// Replace the retry policy, including its retry count.
+    pub fn retry_policy(mut self, policy: RetryPolicy) -> Self {
+        self.retry_policy = policy;
+        self
+    }
+
+    /// The kind of controller on the other end, which decides how states are decoded.
+    pub fn model(mut self, model: ControllerModel) -> Self {
+        self.model = model;
+        self
+    }
+
+    /// Allow commands whose layout hasn't been confirmed on a real controller:
+    /// V5 rumble, screen text and firmware uploads. Off by default, so they
+    /// fail without being sent.
+    pub fn experimental(mut self, enabled: bool) -> Self {
+        self.experimental = enabled;
+        self
+    }
+
+    pub fn is_experimental(&self) -> bool {
+        self.experimental
+    }
+
+    pub(crate) fn check_experimental(&self, feature: &'static str) -> Result<(), ProtocolError> {
+        if self.experimental {
+            Ok(())
+        } else {
+            Err(ProtocolError::Experimental(feature))
+        }
+    }
+
+    pub(crate) fn timeout_for(&self, cmd: Command) -> Duration {
+        if cmd.is_file_transfer() {
+            self.transfer_timeout
+        } else {
+            self.timeout
+        }
+    }
+
+    pub fn build(self, transport: Box<dyn Transport>) -> VexController {
+        VexController {
+            transport,
+            stats: LinkStats::default(),
+            settings: self,
+        }
+    }
+}
+
+impl Default for VexControllerBuilder {
+    fn default() -> Self {
+        Self::new()
     }
 }
 
 pub struct VexController {
     transport: Box<dyn Transport>,
+    stats: LinkStats,
+    settings: VexControllerBuilder,
 }
 
 impl VexController {
     pub fn new(transport: Box<dyn Transport>) -> Self {
-        Self { transport }
+        VexControllerBuilder::new().build(transport)
+    }
+
+    pub fn builder() -> VexControllerBuilder {
+        VexControllerBuilder::new()
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        self.stats
+    }
+
+    pub fn model(&self) -> ControllerModel {
+        self.settings.model
+    }
+
+    pub fn retry_policy(&self) -> &RetryPolicy {
+        &self.settings.retry_policy
+    }
+
+    /// Fail unless experimental commands were enabled on the builder.
+    pub(crate) fn check_experimental(&self, feature: &'static str) -> Result<(), ProtocolError> {
+        self.settings.check_experimental(feature)
+    }
+
+    /// Replace the policy `send_command` uses to decide which failures to retry.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.settings.retry_policy = policy;
+    }
+
+    /// Send a command and wait for the reply that answers it, retrying as the
+    /// retry policy allows. Failures that need a reconnect are returned as is.
+    pub fn send_command(&mut self, cmd1: u8, cmd2: u8, data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        self.send_command_timeout(cmd1, cmd2, data, self.settings.timeout)
     }
 
-    pub fn send_command(&mut self, cmd1: u8, cmd2: u8, data: &[u8]) -> Result<Vec<u8>> {
+    /// Like `send_command`, with its own reply timeout.
+    pub fn send_command_timeout(
+        &mut self,
+        cmd1: u8,
+        cmd2: u8,
+        data: &[u8],
+        timeout: Duration,
+    ) -> Result<Vec<u8>, ProtocolError> {
+        let mut attempt = 0;
+        let mut backoff = self.settings.backoff;
+        loop {
+            attempt += 1;
+            match self.try_command(cmd1, cmd2, data, timeout) {
+                Ok(payload) => return Ok(payload),
+                Err(e) if self.settings.retry_policy.decide(&e, attempt) == RetryAction::Retry => {
+                    debug!("Retrying {:02X} {:02X} in {:?} (attempt {}): {}", cmd1, cmd2, backoff, attempt, e);
+                    std::thread::sleep(backoff);
+                    backoff = backoff.saturating_mul(2);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn try_command(&mut self, cmd1: u8, cmd2: u8, data: &[u8], timeout: Duration) -> Result<Vec<u8>, ProtocolError> {
         let command = Protocol::encode_command(cmd1, cmd2, data);
         
         self.transport.clear_buffer()?;
         self.transport.send_bytes(&command)?;
 
-        let mut buffer = vec![0u8; 1024];
-        let mut packet_buffer = Vec::new();
+        let mut buffer = vec![0u8; self.settings.buffer_size];
+        let mut decoder = FrameDecoder::responses();
+        let mut matcher = ReplyMatcher::new(cmd1, cmd2);
         let start = Instant::now();
 
-        while start.elapsed() < Duration::from_secs(2) {
+        while start.elapsed() < timeout {
             let n = self.transport.receive_bytes(&mut buffer)?;
             if n > 0 {
-                packet_buffer.extend_from_slice(&buffer[..n]);
-                
-                // Framing loop
-                while packet_buffer.len() >= 2 {
-                    if let Some(start_idx) = packet_buffer.windows(2).position(|w| w == HEADERR) {
-                        if start_idx > 0 {
-                            packet_buffer.drain(0..start_idx);
-                        }
-                        
-                        if packet_buffer.len() < 5 {
-                            break;
-                        }
-
-                        let (len, header_size) = if (packet_buffer[3] & 0x80) != 0 {
-                            let len = ((packet_buffer[3] & 0x7F) as usize) << 8 | (packet_buffer[4] as usize);
-                            (len, 5)
-                        } else {
-                            (packet_buffer[3] as usize, 4)
-                        };
-                        
-                        let packet_len = header_size + len;
-                        if packet_buffer.len() >= packet_len {
-                            let packet = &packet_buffer[..packet_len];
-                            if let Some(payload) = Protocol::decode_response(packet) {
-                                debug!("Raw response: {:02X?}", payload);
-                                return Ok(payload);
-                            } else {
-                                // CRC failed or invalid, remove header and try again
-                                packet_buffer.drain(0..2);
-                            }
-                        } else {
-                            break;
-                        }
-                    } else {
-                        packet_buffer.clear();
-                        break;
-                    }
+                decoder.push(&buffer[..n]);
+                if let Some(payload) = matcher.next_reply(&mut decoder, &mut self.stats) {
+                    return Ok(payload);
                 }
             }
-            std::thread::sleep(Duration::from_millis(10));
+            std::thread::sleep(self.settings.read_delay);
         }
-        
-        bail!("Timeout waiting for response");
+
+        Err(matcher.timed_out(&mut self.stats))
     }
 
-    pub fn get_state(&mut self) -> Result<ControllerState> {
-        let payload = self.send_command(Command::ControllerCdc as u8, ControllerSubCommand::GetState as u8, &[])?;
-        Protocol::parse_controller_state(&payload).ok_or_else(|| anyhow::anyhow!("Failed to parse state"))
+    /// Send an extended system command and return the reply data after the ACK byte.
+    pub fn send_system_command(&mut self, cmd: Command, data: &[u8]) -> Result<Vec<u8>, ProtocolError> {
+        let timeout = self.settings.timeout_for(cmd);
+        let payload = self.send_command_timeout(Command::SystemCdc as u8, cmd as u8, data, timeout)?;
+        system_reply_data(cmd, &payload)
     }
 
-    pub fn get_versions(&mut self) -> Result<String> {
-        let payload = self.send_command(Command::ControllerCdc as u8, ControllerSubCommand::GetVersions as u8, &[])?;
-        if payload.len() > 1 {
-            Ok(String::from_utf8_lossy(&payload[1..]).to_string())
-        } else {
-            bail!("Invalid version payload")
-        }
+    /// Send a request and decode its reply.
+    fn request<T>(&mut self, request: Request<T>) -> Result<T, ProtocolError> {
+        let payload = match request.target {
+            Target::Controller(subcommand) => self.send_command(Command::ControllerCdc as u8, subcommand, &request.data)?,
+            Target::System(cmd) => self.send_system_command(cmd, &request.data)?,
+        };
+        (request.decode)(self.settings.model, &payload)
     }
 
-    pub fn get_pair_id(&mut self) -> Result<u8> {
-        let payload = self.send_command(Command::ControllerCdc as u8, ControllerSubCommand::GetPairId as u8, &[])?;
-        if payload.len() > 1 {
-            Ok(payload[1])
-        } else {
-            bail!("Invalid pair ID payload")
-        }
+    pub fn get_state(&mut self) -> Result<ControllerState, ProtocolError> {
+        self.request(Request::get_state())
     }
 
-    pub fn start_calibration(&mut self) -> Result<()> {
-        self.send_command(Command::ControllerCdc as u8, ControllerSubCommand::StartJsCal as u8, &[])?;
-        Ok(())
+    pub fn get_versions(&mut self) -> Result<ControllerVersions, ProtocolError> {
+        self.request(Request::get_versions())
     }
 
-    pub fn abort_calibration(&mut self) -> Result<()> {
-        self.send_command(Command::ControllerCdc as u8, ControllerSubCommand::AbortJsCal as u8, &[])?;
-        Ok(())
+    pub fn get_pair_id(&mut self) -> Result<u8, ProtocolError> {
+        self.request(Request::get_pair_id())
+    }
+
+    pub fn set_pair_id(&mut self, pair_id: u8) -> Result<(), ProtocolError> {
+        self.request(Request::set_pair_id(pair_id))
+    }
+
+    pub fn start_calibration(&mut self) -> Result<(), ProtocolError> {
+        self.request(Request::start_calibration())
+    }
+
+    pub fn abort_calibration(&mut self) -> Result<(), ProtocolError> {
+        self.request(Request::abort_calibration())
+    }
+
+    pub fn get_device_state(&mut self) -> Result<DeviceState, ProtocolError> {
+        self.request(Request::get_device_state())
+    }
+
+    /// Enter or leave factory test mode. `GetTestData` only answers in test mode.
+    pub fn set_test_mode(&mut self, enabled: bool) -> Result<(), ProtocolError> {
+        self.request(Request::set_test_mode(enabled))
+    }
+
+    pub fn get_test_data(&mut self) -> Result<TestData, ProtocolError> {
+        self.request(Request::get_test_data())
+    }
+
+    pub fn get_system_status(&mut self) -> Result<SystemStatus, ProtocolError> {
+        self.request(Request::get_system_status())
+    }
+
+    /// Play a rumble pattern on a V5 controller: `.` short, `-` long, ` ` pause.
+    pub fn rumble(&mut self, pattern: &str) -> Result<(), ProtocolError> {
+        self.request(Request::rumble(&self.settings, pattern)?)
+    }
+
+    /// Replace one line, 0 to 2, of the V5 controller screen.
+    pub fn set_screen_line(&mut self, line: u8, text: &str) -> Result<(), ProtocolError> {
+        self.request(Request::set_screen_line(&self.settings, line, text)?)
+    }
+
+    /// Send a factory ping and return the round-trip time.
+    pub fn ping(&mut self) -> Result<Duration, ProtocolError> {
+        let start = Instant::now();
+        self.request(Request::ping())?;
+        Ok(start.elapsed())
+    }
+
+    /// Poll the state every `interval` and yield what changed between polls.
+    ///
+    /// The blocking counterpart of the async controller's `events`: the first
+    /// state read is the baseline and produces no events, failed polls are
+    /// yielded as errors and polling carries on until the iterator is dropped.
+    pub fn events(&mut self, interval: Duration) -> Events<'_> {
+        Events::new(self, interval)
     }
 }
@@ -1,9 +1,14 @@
-use crate::transport::Transport;
-use anyhow::{Context, Result};
+use crate::model::{ControllerModel, DeviceFamily, VEX_VID};
+use crate::transport::{AsyncTransport, Transport};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
 use serialport::SerialPort;
+use std::fmt;
 use std::io::{self, Write};
 use std::time::Duration;
-use tracing::info;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio_serial::{SerialPortBuilderExt, SerialStream};
+use tracing::{debug, info};
 
 pub struct SerialTransport {
     port: Box<dyn SerialPort>,
@@ -17,53 +22,341 @@ impl SerialTransport {
             .open()
             .context("Failed to open serial port")?;
 
-        port.write_data_terminal_ready(true)?;
-        port.write_request_to_send(true)?;
+        enable_control_lines(port.as_mut(), &name);
+        info!("Connected to {}", name);
+
+        Ok(Self { port })
+    }
+}
+
+/// Serial transport for a tokio runtime. Must be created inside one.
+pub struct AsyncSerialTransport {
+    port: SerialStream,
+}
+
+impl AsyncSerialTransport {
+    pub fn new(port_name: Option<String>) -> Result<Self> {
+        let name = find_port(port_name)?;
+        let mut port = tokio_serial::new(&name, 115200)
+            .open_native_async()
+            .context("Failed to open serial port")?;
 
+        enable_control_lines(&mut port, &name);
         info!("Connected to {}", name);
 
         Ok(Self { port })
     }
 }
 
-impl Transport for SerialTransport {
-    fn send_bytes(&mut self, data: &[u8]) -> Result<()> {
-        self.port.write_all(data)?;
+#[async_trait]
+impl AsyncTransport for AsyncSerialTransport {
+    async fn send_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        AsyncWriteExt::write_all(&mut self.port, data).await
+    }
+
+    async fn receive_bytes(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        match AsyncReadExt::read(&mut self.port, buffer).await {
+            // The port only reports end of file once the device is gone
+            Ok(0) => Err(io::ErrorKind::UnexpectedEof.into()),
+            result => result,
+        }
+    }
+
+    fn clear_buffer(&mut self) -> io::Result<()> {
+        self.port.clear(serialport::ClearBuffer::All)?;
         Ok(())
     }
+}
+
+fn enable_control_lines(port: &mut dyn SerialPort, name: &str) {
+    // Pseudo-terminals have no modem control lines, so don't fail on them
+    if let Err(e) = port.write_data_terminal_ready(true) {
+        debug!("Could not set DTR on {}: {}", name, e);
+    }
+    if let Err(e) = port.write_request_to_send(true) {
+        debug!("Could not set RTS on {}: {}", name, e);
+    }
+}
 
-    fn receive_bytes(&mut self, buffer: &mut [u8]) -> Result<usize> {
+impl Transport for SerialTransport {
+    fn send_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        self.port.write_all(data)
+    }
+
+    fn receive_bytes(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
         match self.port.read(buffer) {
             Ok(n) => Ok(n),
             Err(ref e) if e.kind() == io::ErrorKind::TimedOut => Ok(0),
-            Err(e) => Err(e.into()),
+            Err(e) => Err(e),
         }
     }
 
-    fn clear_buffer(&mut self) -> Result<()> {
+    fn clear_buffer(&mut self) -> io::Result<()> {
         self.port.clear(serialport::ClearBuffer::All)?;
         Ok(())
     }
 }
 
-fn find_port(port_name: Option<String>) -> Result<String> {
-    if let Some(port) = port_name {
-        Ok(port)
-    } else {
-        let ports = serialport::available_ports()?;
-        let vex_port = ports.iter().find(|p| {
-            if let serialport::SerialPortType::UsbPort(info) = &p.port_type {
-                info.vid == 10376 && info.pid == 528
-            } else {
-                false
+/// Which of a VEX device's USB serial interfaces a port is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortRole {
+    /// Takes protocol commands. Controllers only have this one.
+    System,
+    /// Carries a brain's user program output
+    User,
+}
+
+impl PortRole {
+    /// Tell the ports apart by USB interface number or, where that isn't
+    /// reported, by product name. Brains put the system port on interface 0
+    /// and the user port on 2. macOS reports the data interface one above each.
+    fn from_usb(interface: Option<u8>, product: Option<&str>) -> Self {
+        match (interface, product) {
+            (Some(interface), _) if interface >= 2 => Self::User,
+            (Some(_), _) => Self::System,
+            (None, Some(product)) if product.contains("User") => Self::User,
+            (None, _) => Self::System,
+        }
+    }
+}
+
+impl fmt::Display for PortRole {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::System => write!(f, "system"),
+            Self::User => write!(f, "user"),
+        }
+    }
+}
+
+/// A serial port belonging to an attached VEX device.
+#[derive(Debug, Clone)]
+pub struct VexPort {
+    pub name: String,
+    pub family: DeviceFamily,
+    pub serial_number: Option<String>,
+    pub role: PortRole,
+}
+
+impl VexPort {
+    /// Whether this is a controller's system port, the one the driver opens.
+    pub fn is_controller(&self) -> bool {
+        self.role == PortRole::System && self.family.controller_model().is_some()
+    }
+}
+
+/// Every serial port of every attached VEX device.
+pub fn list_devices() -> Result<Vec<VexPort>> {
+    let mut ports: Vec<VexPort> = serialport::available_ports()?.iter().filter_map(vex_port).collect();
+    ports.sort_by(|a, b| (&a.serial_number, &a.name).cmp(&(&b.serial_number, &b.name)));
+    Ok(ports)
+}
+
+fn vex_port(port: &serialport::SerialPortInfo) -> Option<VexPort> {
+    let serialport::SerialPortType::UsbPort(info) = &port.port_type else {
+        return None;
+    };
+    if info.vid != VEX_VID {
+        return None;
+    }
+
+    Some(VexPort {
+        name: port.port_name.clone(),
+        family: DeviceFamily::from_pid(info.pid),
+        serial_number: info.serial_number.clone(),
+        role: PortRole::from_usb(info.interface, info.product.as_deref()),
+    })
+}
+
+/// The controller port `selector` asks for among `ports`. A port picked by
+/// name is only returned if it is a controller's system port.
+fn select_controller<'a>(ports: &'a [VexPort], selector: &PortSelector) -> Option<&'a VexPort> {
+    let mut controllers = ports.iter().filter(|port| port.is_controller());
+    match selector {
+        PortSelector::Auto => controllers.next(),
+        PortSelector::Port(name) => controllers.find(|port| &port.name == name),
+        PortSelector::Serial(serial) => controllers.find(|port| port.serial_number.as_deref() == Some(serial)),
+    }
+}
+
+/// How to pick the controller to open.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum PortSelector {
+    /// The first controller found
+    #[default]
+    Auto,
+    /// This port, whatever is behind it
+    Port(String),
+    /// The controller with this USB serial number
+    Serial(String),
+}
+
+/// A controller's serial port and, when it is a known USB device, its model
+/// and serial number.
+#[derive(Debug, Clone)]
+pub struct ControllerPort {
+    pub name: String,
+    pub model: Option<ControllerModel>,
+    pub serial_number: Option<String>,
+}
+
+impl ControllerPort {
+    fn from_vex_port(port: &VexPort) -> Self {
+        Self {
+            name: port.name.clone(),
+            model: port.family.controller_model(),
+            serial_number: port.serial_number.clone(),
+        }
+    }
+}
+
+/// Find the controller's system port as `selector` asks.
+pub fn find_controller(selector: &PortSelector) -> Result<ControllerPort> {
+    let name = match selector {
+        PortSelector::Port(name) => name,
+        PortSelector::Serial(serial) => {
+            let ports = list_devices()?;
+            return match select_controller(&ports, selector) {
+                Some(port) => {
+                    info!("Found {} {} at {}", port.family, serial, port.name);
+                    Ok(ControllerPort::from_vex_port(port))
+                }
+                None => bail!("No VEX controller with serial number {} found", serial),
+            };
+        }
+        PortSelector::Auto => {
+            let ports = list_devices()?;
+            if let Some(port) = select_controller(&ports, selector) {
+                info!("Found {} at {}", port.family, port.name);
+                return Ok(ControllerPort::from_vex_port(port));
+            }
+            match ports.first() {
+                Some(other) => bail!("No VEX controller found, only a {} at {}", other.family, other.name),
+                None => bail!("No VEX controller found"),
             }
-        });
+        }
+    };
 
-        if let Some(port) = vex_port {
-            info!("Found VEX IQ Gen 2 Controller at {}", port.port_name);
-            Ok(port.port_name.clone())
-        } else {
-            anyhow::bail!("No VEX IQ Gen 2 Controller found");
+    // A port that can't be enumerated, like a pseudo-terminal, may still be opened
+    let ports = list_devices().unwrap_or_default();
+    match select_controller(&ports, selector) {
+        Some(port) => Ok(ControllerPort::from_vex_port(port)),
+        None => {
+            debug!("{} is not a known VEX controller", name);
+            Ok(ControllerPort { name: name.clone(), model: None, serial_number: None })
         }
     }
 }
+
+/// Every attached controller's system port, in [`list_devices`] order.
+pub fn find_controllers() -> Result<Vec<ControllerPort>> {
+    let ports: Vec<ControllerPort> = list_devices()?
+        .iter()
+        .filter(|p| p.is_controller())
+        .map(ControllerPort::from_vex_port)
+        .collect();
+    if ports.is_empty() {
+        bail!("No VEX controller found");
+    }
+    for port in &ports {
+        info!("Found {} at {}", port.model.unwrap_or_default(), port.name);
+    }
+    Ok(ports)
+}
+
+fn find_port(port_name: Option<String>) -> Result<String> {
+    match port_name {
+        Some(name) => Ok(name),
+        None => Ok(find_controller(&PortSelector::Auto)?.name),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serialport::{SerialPortInfo, SerialPortType, UsbPortInfo};
+
+    fn usb(name: &str, vid: u16, pid: u16, interface: Option<u8>, product: Option<&str>) -> SerialPortInfo {
+        SerialPortInfo {
+            port_name: name.to_string(),
+            port_type: SerialPortType::UsbPort(UsbPortInfo {
+                vid,
+                pid,
+                serial_number: Some(format!("SN-{}", name)),
+                manufacturer: None,
+                product: product.map(str::to_string),
+                interface,
+            }),
+        }
+    }
+
+    fn role(interface: Option<u8>, product: Option<&str>) -> Option<PortRole> {
+        vex_port(&usb("ttyACM0", VEX_VID, 0x0501, interface, product)).map(|port| port.role)
+    }
+
+    #[test]
+    fn interfaces_map_to_roles() {
+        assert_eq!(role(Some(0), None), Some(PortRole::System));
+        // macOS reports the data interface
+        assert_eq!(role(Some(1), None), Some(PortRole::System));
+        assert_eq!(role(Some(2), None), Some(PortRole::User));
+        assert_eq!(role(Some(3), None), Some(PortRole::User));
+        // The interface number wins over the product name
+        assert_eq!(role(Some(0), Some("VEX V5 User Port")), Some(PortRole::System));
+    }
+
+    #[test]
+    fn product_names_map_to_roles_without_an_interface() {
+        assert_eq!(role(None, Some("VEX V5 User Port")), Some(PortRole::User));
+        assert_eq!(role(None, Some("VEX V5 Communications Port")), Some(PortRole::System));
+        assert_eq!(role(None, None), Some(PortRole::System));
+    }
+
+    #[test]
+    fn only_vex_usb_ports_are_listed() {
+        assert!(vex_port(&usb("ttyUSB0", 0x1234, 0x0210, Some(0), None)).is_none());
+        let pty = SerialPortInfo { port_name: "/dev/pts/3".to_string(), port_type: SerialPortType::Unknown };
+        assert!(vex_port(&pty).is_none());
+
+        let port = vex_port(&usb("ttyACM0", VEX_VID, 0x0210, Some(0), None)).unwrap();
+        assert_eq!(port.family, DeviceFamily::IqGen2Controller);
+        assert_eq!(port.serial_number.as_deref(), Some("SN-ttyACM0"));
+        assert!(port.is_controller());
+    }
+
+    fn ports() -> Vec<VexPort> {
+        [
+            usb("brain-system", VEX_VID, 0x0501, Some(0), None),
+            usb("brain-user", VEX_VID, 0x0501, Some(2), None),
+            usb("v5", VEX_VID, 0x0503, Some(0), None),
+            usb("iq2", VEX_VID, 0x0210, Some(0), None),
+        ]
+        .iter()
+        .filter_map(vex_port)
+        .collect()
+    }
+
+    fn selected(selector: PortSelector) -> Option<String> {
+        select_controller(&ports(), &selector).map(|port| port.name.clone())
+    }
+
+    #[test]
+    fn auto_picks_the_first_controller() {
+        assert_eq!(selected(PortSelector::Auto).as_deref(), Some("v5"));
+        assert_eq!(select_controller(&ports()[..2], &PortSelector::Auto).map(|port| &port.name), None);
+    }
+
+    #[test]
+    fn serial_numbers_only_pick_controllers() {
+        assert_eq!(selected(PortSelector::Serial("SN-iq2".into())).as_deref(), Some("iq2"));
+        assert_eq!(selected(PortSelector::Serial("SN-brain-system".into())), None);
+        assert_eq!(selected(PortSelector::Serial("SN-missing".into())), None);
+    }
+
+    #[test]
+    fn port_names_only_pick_controllers() {
+        assert_eq!(selected(PortSelector::Port("iq2".into())).as_deref(), Some("iq2"));
+        assert_eq!(selected(PortSelector::Port("brain-user".into())), None);
+        assert_eq!(selected(PortSelector::Port("/dev/pts/3".into())), None);
+    }
+}
@@ -0,0 +1,562 @@
+use crate::error::{NACK_NO_TRANSFER, NACK_PROGRAM_CRC};
+use crate::firmware::TransferExit;
+use crate::frame::FrameDecoder;
+use crate::model::ControllerModel;
+use crate::protocol::{
+    Button, Command, ControllerState, ControllerSubCommand, ControllerVersions, DeviceState, Protocol, RawState,
+    SystemStatus, TestData, Version, ACK, CRC32, FACTORY_PAIR_ID, RUMBLE_SUBCOMMAND, SCREEN_LINES,
+    SCREEN_TEXT_SUBCOMMAND,
+};
+use crate::transport::{AsyncTransport, Transport};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use byteorder::{ByteOrder, LittleEndian};
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+
+type Action = Box<dyn FnMut(&mut ControllerState) + Send>;
+
+/// Largest `FileWrite` payload the simulator advertises
+const MAX_PACKET_SIZE: u16 = 1024;
+
+struct SimTransfer {
+    address: u32,
+    crc: u32,
+    data: Vec<u8>,
+}
+
+/// What happens to the reply to an upcoming command.
+enum ReplyFault {
+    /// The reply is lost
+    Drop,
+    /// This frame goes out instead of the reply
+    Replace(Vec<u8>),
+}
+
+struct ScriptStep {
+    at: Duration,
+    action: Action,
+}
+
+struct SimState {
+    controller: ControllerState,
+    versions: ControllerVersions,
+    pair_id: u8,
+    script: VecDeque<ScriptStep>,
+    started: Option<Instant>,
+    transfer: Option<SimTransfer>,
+    firmware: Option<Vec<u8>>,
+    system_status: SystemStatus,
+    device_state: DeviceState,
+    test_mode: bool,
+    injected: VecDeque<Vec<u8>>,
+    /// Faults for the replies to the next commands, in order
+    faults: VecDeque<ReplyFault>,
+    screen: [String; SCREEN_LINES as usize],
+    rumbles: Vec<String>,
+}
+
+impl SimState {
+    /// Apply every scripted step whose time has come.
+    fn advance(&mut self) {
+        let Some(started) = self.started else {
+            return;
+        };
+        let elapsed = started.elapsed();
+        while self.script.front().is_some_and(|step| step.at <= elapsed) {
+            if let Some(mut step) = self.script.pop_front() {
+                (step.action)(&mut self.controller);
+            }
+        }
+    }
+}
+
+/// A fake VEX IQ Gen 2 or V5 controller that answers the device side of the protocol.
+///
+/// Commands written by the host are parsed as `HEADERS` frames and answered with
+/// `HEADERR` frames, so a `VexController` can run on top of it without hardware.
+/// Use [`SimulatedController::handle`] to drive the simulated inputs once the
+/// transport has been handed to a `VexController`.
+pub struct SimulatedController {
+    shared: Arc<Mutex<SimState>>,
+    decoder: FrameDecoder,
+    outbound: VecDeque<u8>,
+}
+
+/// Cloneable handle for scripting a [`SimulatedController`].
+#[derive(Clone)]
+pub struct SimulatorHandle {
+    shared: Arc<Mutex<SimState>>,
+}
+
+impl SimulatedController {
+    pub fn new() -> Self {
+        Self::with_model(ControllerModel::default())
+    }
+
+    /// Simulate a controller of the given model, with its buttons and product type.
+    pub fn with_model(model: ControllerModel) -> Self {
+        let controller = ControllerState {
+            left_x: 127,
+            left_y: 127,
+            right_x: 127,
+            right_y: 127,
+            battery: 100,
+            model,
+            ..Default::default()
+        };
+
+        Self {
+            shared: Arc::new(Mutex::new(SimState {
+                controller,
+                versions: ControllerVersions::from_text("VEX IQ2 Controller 1.0.4 (simulated)"),
+                pair_id: FACTORY_PAIR_ID,
+                script: VecDeque::new(),
+                started: None,
+                transfer: None,
+                firmware: None,
+                system_status: SystemStatus {
+                    product_type: model.product_type(),
+                    system_version: Version { major: 1, minor: 0, build: 4, beta: 0 },
+                    bootloader_version: Version { major: 1, minor: 0, build: 0, beta: 0 },
+                    flags: 0,
+                    extra: Vec::new(),
+                },
+                device_state: DeviceState::default(),
+                test_mode: false,
+                injected: VecDeque::new(),
+                faults: VecDeque::new(),
+                screen: Default::default(),
+                rumbles: Vec::new(),
+            })),
+            decoder: FrameDecoder::commands(),
+            outbound: VecDeque::new(),
+        }
+    }
+
+    pub fn handle(&self) -> SimulatorHandle {
+        SimulatorHandle { shared: self.shared.clone() }
+    }
+
+    fn process_commands(&mut self) {
+        while let Some(frame) = self.decoder.next_frame() {
+            match frame {
+                Ok(frame) => {
+                    if let Some(cmd2) = frame.subcommand() {
+                        self.respond(frame.command, cmd2, frame.data());
+                    }
+                }
+                Err(e) => debug!("Simulator dropped command: {}", e),
+            }
+        }
+    }
+
+    fn respond(&mut self, cmd1: u8, cmd2: u8, data: &[u8]) {
+        let mut shared = self.shared.lock().unwrap();
+        // The script clock starts with the first command from the host
+        shared.started.get_or_insert_with(Instant::now);
+        shared.advance();
+
+        let payload = match Command::try_from(cmd1) {
+            Ok(Command::ControllerCdc) => Self::controller_reply(&mut shared, cmd2, data),
+            Ok(Command::SystemCdc) => Self::system_reply(&mut shared, cmd2, data),
+            _ => None,
+        };
+
+        // Injected frames go out ahead of the real reply, like a late answer to an earlier command
+        for frame in shared.injected.drain(..) {
+            self.outbound.extend(frame);
+        }
+
+        if payload.is_some() {
+            match shared.faults.pop_front() {
+                Some(ReplyFault::Drop) => {
+                    debug!("Simulator dropping the reply to {:02X} {:02X}", cmd1, cmd2);
+                    return;
+                }
+                Some(ReplyFault::Replace(frame)) => {
+                    debug!("Simulator replacing the reply to {:02X} {:02X}", cmd1, cmd2);
+                    self.outbound.extend(frame);
+                    return;
+                }
+                None => {}
+            }
+        }
+
+        match payload {
+            Some(payload) => self.outbound.extend(Protocol::encode_response(cmd1, &payload)),
+            None => debug!("Simulator ignoring command {:02X} {:02X}", cmd1, cmd2),
+        }
+    }
+
+    fn system_reply(shared: &mut SimState, cmd2: u8, data: &[u8]) -> Option<Vec<u8>> {
+        match Command::try_from(cmd2) {
+            Ok(Command::FactoryPing) => Some(vec![cmd2, ACK]),
+            Ok(Command::SysStatus) => {
+                let mut reply = vec![cmd2, ACK];
+                reply.extend_from_slice(&shared.system_status.to_bytes());
+                Some(reply)
+            }
+            Ok(Command::FileInit) => {
+                if data.len() < 16 {
+                    return None;
+                }
+                let length = LittleEndian::read_u32(&data[4..8]);
+                let address = LittleEndian::read_u32(&data[8..12]);
+                let crc = LittleEndian::read_u32(&data[12..16]);
+
+                // Re-opening the same image keeps what was written, so uploads can resume
+                let resumable = shared.transfer.as_ref().is_some_and(|t| {
+                    t.address == address && t.crc == crc && t.data.len() == length as usize
+                });
+                if !resumable {
+                    shared.transfer = Some(SimTransfer { address, crc, data: vec![0; length as usize] });
+                }
+
+                let mut reply = vec![cmd2, ACK];
+                reply.extend_from_slice(&MAX_PACKET_SIZE.to_le_bytes());
+                reply.extend_from_slice(&length.to_le_bytes());
+                reply.extend_from_slice(&crc.to_le_bytes());
+                Some(reply)
+            }
+            Ok(Command::FileWrite) => {
+                let Some(transfer) = shared.transfer.as_mut() else {
+                    return Some(vec![cmd2, NACK_NO_TRANSFER]);
+                };
+                if data.len() < 4 {
+                    return None;
+                }
+                let offset = LittleEndian::read_u32(&data[0..4]).wrapping_sub(transfer.address) as usize;
+                let chunk = &data[4..];
+                // Writes are padded to 4 bytes, drop anything past the end of the image
+                let end = (offset + chunk.len()).min(transfer.data.len());
+                if offset < end {
+                    transfer.data[offset..end].copy_from_slice(&chunk[..end - offset]);
+                }
+                Some(vec![cmd2, ACK])
+            }
+            Ok(Command::FileExit) => {
+                let Some(transfer) = shared.transfer.take() else {
+                    return Some(vec![cmd2, NACK_NO_TRANSFER]);
+                };
+                if data.first() == Some(&(TransferExit::Abort as u8)) {
+                    return Some(vec![cmd2, ACK]);
+                }
+                if CRC32.checksum(&transfer.data) != transfer.crc {
+                    return Some(vec![cmd2, NACK_PROGRAM_CRC]);
+                }
+                shared.firmware = Some(transfer.data);
+                Some(vec![cmd2, ACK])
+            }
+            _ => None,
+        }
+    }
+
+    fn controller_reply(shared: &mut SimState, cmd2: u8, data: &[u8]) -> Option<Vec<u8>> {
+        let payload = match ControllerSubCommand::try_from(cmd2) {
+            Ok(ControllerSubCommand::GetState) => shared.controller.to_payload(),
+            Ok(ControllerSubCommand::GetVersions) => shared.versions.to_payload(),
+            Ok(ControllerSubCommand::GetPairId) => vec![cmd2, shared.pair_id],
+            Ok(ControllerSubCommand::DevState) => shared.device_state.to_payload(),
+            Ok(ControllerSubCommand::TestCmd) => {
+                shared.test_mode = *data.first()? != 0;
+                vec![cmd2, shared.test_mode as u8]
+            }
+            Ok(ControllerSubCommand::GetTestData) if shared.test_mode => test_data(&shared.controller).to_payload(),
+            Ok(ControllerSubCommand::SetPairId) => {
+                let pair_id = *data.first()?;
+                shared.pair_id = pair_id;
+                vec![cmd2, pair_id]
+            }
+            Ok(ControllerSubCommand::StartJsCal) => {
+                shared.controller.cal_active = true;
+                shared.controller.cal_left = false;
+                shared.controller.cal_right = false;
+                vec![cmd2]
+            }
+            Ok(ControllerSubCommand::AbortJsCal) => {
+                shared.controller.cal_active = false;
+                shared.controller.cal_left = false;
+                shared.controller.cal_right = false;
+                vec![cmd2]
+            }
+            Err(RUMBLE_SUBCOMMAND) if shared.controller.model.has_rumble() => {
+                let pattern = String::from_utf8_lossy(data).into_owned();
+                info!("Rumble '{}'", pattern);
+                shared.rumbles.push(pattern);
+                vec![cmd2]
+            }
+            Err(SCREEN_TEXT_SUBCOMMAND) if shared.controller.model.has_screen() => {
+                let (&line, text) = data.split_first()?;
+                let text = String::from_utf8_lossy(text).trim_end().to_string();
+                info!("Screen line {}: {}", line + 1, text);
+                *shared.screen.get_mut(line as usize)? = text;
+                vec![cmd2]
+            }
+            _ => return None,
+        };
+        Some(payload)
+    }
+}
+
+impl Default for SimulatedController {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Transport for SimulatedController {
+    fn send_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        self.decoder.push(data);
+        self.process_commands();
+        Ok(())
+    }
+
+    fn receive_bytes(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        let n = buffer.len().min(self.outbound.len());
+        for (dst, src) in buffer.iter_mut().zip(self.outbound.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+
+    fn clear_buffer(&mut self) -> io::Result<()> {
+        self.decoder.clear();
+        self.outbound.clear();
+        Ok(())
+    }
+}
+
+/// Lets the simulator stand in for a serial port on a tokio runtime. Replies
+/// are produced as soon as a command is sent, so nothing here waits.
+#[async_trait]
+impl AsyncTransport for SimulatedController {
+    async fn send_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        Transport::send_bytes(self, data)
+    }
+
+    async fn receive_bytes(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        Transport::receive_bytes(self, buffer)
+    }
+
+    fn clear_buffer(&mut self) -> io::Result<()> {
+        Transport::clear_buffer(self)
+    }
+}
+
+impl SimulatorHandle {
+    /// Current simulated state, after applying any steps that are due.
+    pub fn state(&self) -> ControllerState {
+        let mut shared = self.shared.lock().unwrap();
+        shared.advance();
+        shared.controller
+    }
+
+    /// Change the simulated state immediately.
+    pub fn update(&self, f: impl FnOnce(&mut ControllerState)) {
+        f(&mut self.shared.lock().unwrap().controller);
+    }
+
+    pub fn set_battery(&self, level: u8) {
+        self.update(|state| state.battery = level);
+    }
+
+    pub fn set_versions(&self, text: &str) {
+        self.shared.lock().unwrap().versions = ControllerVersions::from_text(text);
+    }
+
+    /// Lines shown on the simulated V5 screen, without trailing padding.
+    pub fn screen(&self) -> [String; SCREEN_LINES as usize] {
+        self.shared.lock().unwrap().screen.clone()
+    }
+
+    /// Every rumble pattern played so far.
+    pub fn rumbles(&self) -> Vec<String> {
+        self.shared.lock().unwrap().rumbles.clone()
+    }
+
+    pub fn set_pair_id(&self, pair_id: u8) {
+        self.shared.lock().unwrap().pair_id = pair_id;
+    }
+
+    pub fn set_device_state(&self, state: DeviceState) {
+        self.shared.lock().unwrap().device_state = state;
+    }
+
+    pub fn set_system_status(&self, status: SystemStatus) {
+        self.shared.lock().unwrap().system_status = status;
+    }
+
+    /// Schedule a change to the simulated state `at` after the script clock started.
+    ///
+    /// The clock starts with the first command the host sends, or with
+    /// [`SimulatorHandle::restart_clock`]. Steps run in time order; steps
+    /// scheduled for the same time run in the order they were added.
+    pub fn at(&self, at: Duration, action: impl FnMut(&mut ControllerState) + Send + 'static) {
+        let mut shared = self.shared.lock().unwrap();
+        let idx = shared.script.partition_point(|step| step.at <= at);
+        shared.script.insert(idx, ScriptStep { at, action: Box::new(action) });
+    }
+
+    /// Restart the script clock from zero.
+    pub fn restart_clock(&self) {
+        self.shared.lock().unwrap().started = Some(Instant::now());
+    }
+
+    /// Schedule every step of a scenario.
+    pub fn load_scenario(&self, scenario: &Scenario) {
+        for step in &scenario.steps {
+            let assignments = step.assignments.clone();
+            self.at(step.at, move |state| {
+                for (field, value) in &assignments {
+                    // Assignments were validated when the scenario was parsed
+                    let _ = set_field(state, field, value);
+                }
+            });
+        }
+    }
+
+    /// Send an extra reply frame ahead of the reply to the next command.
+    pub fn inject_response(&self, cmd1: u8, payload: &[u8]) {
+        self.shared.lock().unwrap().injected.push_back(Protocol::encode_response(cmd1, payload));
+    }
+
+    /// Lose the replies to the next `count` commands, as if they never arrived.
+    /// The commands themselves still take effect. Queued after any reply
+    /// faults already set up.
+    pub fn drop_replies(&self, count: u32) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.faults.extend((0..count).map(|_| ReplyFault::Drop));
+    }
+
+    /// Answer the next command, after any reply faults already set up, with
+    /// this frame instead of its own reply.
+    pub fn replace_reply(&self, cmd1: u8, payload: &[u8]) {
+        let frame = Protocol::encode_response(cmd1, payload);
+        self.shared.lock().unwrap().faults.push_back(ReplyFault::Replace(frame));
+    }
+
+    /// The last firmware image uploaded and verified through the file transfer commands.
+    pub fn firmware(&self) -> Option<Vec<u8>> {
+        self.shared.lock().unwrap().firmware.clone()
+    }
+
+    /// Number of scripted steps that have not run yet.
+    pub fn pending_steps(&self) -> usize {
+        self.shared.lock().unwrap().script.len()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ScenarioStep {
+    at: Duration,
+    assignments: Vec<(String, String)>,
+}
+
+/// Simulated input over time, read from a scenario file.
+///
+/// Each non-empty line holds a time in milliseconds followed by `field=value`
+/// assignments to [`ControllerState`] fields; `#` starts a comment:
+///
+/// ```text
+/// # time_ms  assignments
+/// 0          battery=90
+/// 500        left_x=255 e_up=1
+/// 1000       left_x=127 e_up=0
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Scenario {
+    steps: Vec<ScenarioStep>,
+}
+
+impl Scenario {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read scenario {}", path.display()))?;
+        Self::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut steps = Vec::new();
+        for (line_no, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            let mut tokens = line.split_whitespace();
+            let Some(time) = tokens.next() else {
+                continue;
+            };
+
+            let at = time
+                .parse::<u64>()
+                .map(Duration::from_millis)
+                .with_context(|| format!("Line {}: invalid time '{}'", line_no + 1, time))?;
+
+            let mut assignments = Vec::new();
+            let mut probe = ControllerState::default();
+            for token in tokens {
+                let Some((field, value)) = token.split_once('=') else {
+                    bail!("Line {}: expected field=value, got '{}'", line_no + 1, token);
+                };
+                set_field(&mut probe, field, value).with_context(|| format!("Line {}", line_no + 1))?;
+                assignments.push((field.to_string(), value.to_string()));
+            }
+
+            steps.push(ScenarioStep { at, assignments });
+        }
+        Ok(Self { steps })
+    }
+
+    /// Time of the last step.
+    pub fn duration(&self) -> Duration {
+        self.steps.iter().map(|step| step.at).max().unwrap_or_default()
+    }
+}
+
+/// Raw readings matching a simulated state.
+fn test_data(state: &ControllerState) -> TestData {
+    let adc = |value: u8| (value as u32 * TestData::ADC_MAX as u32 / 255) as u16;
+    TestData {
+        sticks: [adc(state.left_x), adc(state.left_y), adc(state.right_x), adc(state.right_y)],
+        buttons: state.buttons,
+        model: state.model,
+    }
+}
+
+/// Set a [`ControllerState`] field by name.
+fn set_field(state: &mut ControllerState, field: &str, value: &str) -> Result<()> {
+    let parse_u8 = || value.parse::<u8>().with_context(|| format!("Invalid value '{}' for {}", value, field));
+    let parse_bool = || match value {
+        "1" | "true" | "on" => Ok(true),
+        "0" | "false" | "off" => Ok(false),
+        _ => bail!("Invalid value '{}' for {}", value, field),
+    };
+
+    match field {
+        "left_x" => state.left_x = parse_u8()?,
+        "left_y" => state.left_y = parse_u8()?,
+        "right_x" => state.right_x = parse_u8()?,
+        "right_y" => state.right_y = parse_u8()?,
+        "battery" => state.battery = parse_u8()?,
+        "cal_active" => state.cal_active = parse_bool()?,
+        "cal_left" => state.cal_left = parse_bool()?,
+        "cal_right" => state.cal_right = parse_bool()?,
+        _ if field.starts_with("raw") => {
+            let offset = field[3..]
+                .parse()
+                .ok()
+                .filter(|offset| RawState::UNKNOWN_BYTES.contains(offset))
+                .ok_or_else(|| anyhow!("Unknown field '{}', raw bytes are {:?}", field, RawState::UNKNOWN_BYTES))?;
+            state.raw.set_byte(offset, parse_u8()?);
+        }
+        _ => {
+            let button: Button = field.parse().map_err(|_| anyhow!("Unknown field '{}'", field))?;
+            state.buttons.set(button, parse_bool()?);
+        }
+    }
+    Ok(())
+}
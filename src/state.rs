@@ -0,0 +1,581 @@
+use crate::model::ControllerModel;
+use crate::protocol::ControllerSubCommand;
+use serde::Deserialize;
+use std::fmt;
+use std::str::FromStr;
+
+/// A physical button on any supported controller. The value is its bit in
+/// [`Buttons`]; [`ControllerModel::buttons`] lists the ones each model has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Button {
+    // IQ Gen 2
+    FDown = 0,
+    EDown = 1,
+    FUp = 2,
+    EUp = 3,
+    LDown = 4,
+    LUp = 5,
+    RDown = 6,
+    RUp = 7,
+    L3 = 8,
+    R3 = 9,
+    // V5
+    L1 = 10,
+    L2 = 11,
+    R1 = 12,
+    R2 = 13,
+    Up = 14,
+    Down = 15,
+    Left = 16,
+    Right = 17,
+    X = 18,
+    B = 19,
+    Y = 20,
+    A = 21,
+}
+
+impl Button {
+    pub const ALL: [Button; 22] = [
+        Self::LUp,
+        Self::LDown,
+        Self::RUp,
+        Self::RDown,
+        Self::EUp,
+        Self::EDown,
+        Self::FUp,
+        Self::FDown,
+        Self::L3,
+        Self::R3,
+        Self::L1,
+        Self::L2,
+        Self::R1,
+        Self::R2,
+        Self::Up,
+        Self::Down,
+        Self::Left,
+        Self::Right,
+        Self::X,
+        Self::B,
+        Self::Y,
+        Self::A,
+    ];
+
+    pub fn bit(self) -> u32 {
+        1 << self as u32
+    }
+
+    /// Name used in scenario and config files, such as `e_up`
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::LUp => "l_up",
+            Self::LDown => "l_down",
+            Self::RUp => "r_up",
+            Self::RDown => "r_down",
+            Self::EUp => "e_up",
+            Self::EDown => "e_down",
+            Self::FUp => "f_up",
+            Self::FDown => "f_down",
+            Self::L3 => "l3",
+            Self::R3 => "r3",
+            Self::L1 => "l1",
+            Self::L2 => "l2",
+            Self::R1 => "r1",
+            Self::R2 => "r2",
+            Self::Up => "up",
+            Self::Down => "down",
+            Self::Left => "left",
+            Self::Right => "right",
+            Self::X => "x",
+            Self::B => "b",
+            Self::Y => "y",
+            Self::A => "a",
+        }
+    }
+}
+
+impl fmt::Display for Button {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self {
+            Self::LUp => "L Up",
+            Self::LDown => "L Down",
+            Self::RUp => "R Up",
+            Self::RDown => "R Down",
+            Self::EUp => "E Up",
+            Self::EDown => "E Down",
+            Self::FUp => "F Up",
+            Self::FDown => "F Down",
+            Self::L3 => "L3",
+            Self::R3 => "R3",
+            Self::L1 => "L1",
+            Self::L2 => "L2",
+            Self::R1 => "R1",
+            Self::R2 => "R2",
+            Self::Up => "Up",
+            Self::Down => "Down",
+            Self::Left => "Left",
+            Self::Right => "Right",
+            Self::X => "X",
+            Self::B => "B",
+            Self::Y => "Y",
+            Self::A => "A",
+        };
+        write!(f, "{}", label)
+    }
+}
+
+/// Accepts [`Button::name`] as well as the displayed label, in any case.
+impl FromStr for Button {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let name = s.trim().to_ascii_lowercase().replace(' ', "_");
+        Self::ALL
+            .into_iter()
+            .find(|button| button.name() == name)
+            .ok_or_else(|| format!("Unknown button '{}'", s))
+    }
+}
+
+impl TryFrom<String> for Button {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+/// A joystick axis.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Axis {
+    LeftX,
+    LeftY,
+    RightX,
+    RightY,
+}
+
+impl Axis {
+    pub const ALL: [Axis; 4] = [Self::LeftX, Self::LeftY, Self::RightX, Self::RightY];
+
+    /// Raw reading of a centred stick
+    pub const CENTRE: u8 = 127;
+
+    /// Map a raw reading to -1.0..=1.0. Each side of the centre is scaled on
+    /// its own so both ends reach full scale.
+    pub fn normalize(raw: u8) -> f32 {
+        let offset = raw as f32 - Self::CENTRE as f32;
+        if offset < 0.0 {
+            offset / Self::CENTRE as f32
+        } else {
+            offset / (u8::MAX - Self::CENTRE) as f32
+        }
+    }
+
+    /// Inverse of [`Axis::normalize`]. Values outside -1.0..=1.0 are clamped.
+    pub fn denormalize(value: f32) -> u8 {
+        let value = value.clamp(-1.0, 1.0);
+        let scale = if value < 0.0 { Self::CENTRE } else { u8::MAX - Self::CENTRE };
+        (Self::CENTRE as f32 + value * scale as f32).round() as u8
+    }
+}
+
+/// Set of pressed buttons, one bit per [`Button`].
+///
+/// The IQ Gen 2 buttons sit at the same bits as in its `GetState` button
+/// word; [`ControllerModel::decode_buttons`] converts from each model's wire
+/// layout.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Buttons(u32);
+
+impl Buttons {
+    pub const NONE: Buttons = Buttons(0);
+
+    pub fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    pub fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub fn pressed(self, button: Button) -> bool {
+        self.0 & button.bit() != 0
+    }
+
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        if pressed {
+            self.0 |= button.bit();
+        } else {
+            self.0 &= !button.bit();
+        }
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Pressed buttons, in [`Button::ALL`] order.
+    pub fn iter(self) -> ButtonsIter {
+        ButtonsIter { buttons: self, next: 0 }
+    }
+}
+
+impl FromIterator<Button> for Buttons {
+    fn from_iter<I: IntoIterator<Item = Button>>(iter: I) -> Self {
+        let mut buttons = Self::NONE;
+        for button in iter {
+            buttons.set(button, true);
+        }
+        buttons
+    }
+}
+
+impl IntoIterator for Buttons {
+    type Item = Button;
+    type IntoIter = ButtonsIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+pub struct ButtonsIter {
+    buttons: Buttons,
+    next: usize,
+}
+
+impl Iterator for ButtonsIter {
+    type Item = Button;
+
+    fn next(&mut self) -> Option<Button> {
+        while let Some(&button) = Button::ALL.get(self.next) {
+            self.next += 1;
+            if self.buttons.pressed(button) {
+                return Some(button);
+            }
+        }
+        None
+    }
+}
+
+/// The undecoded `GetState` reply payload.
+///
+/// Byte 0 is the subcommand, 1-4 the sticks, 8 the status flags, 9 and 10
+/// the buttons and 11 the battery. Bytes 5-7, 12 and 13 have not been
+/// identified yet, nor have status bits 0-3 and 7 or the bits of byte 10
+/// above the model's buttons.
+/// They are kept here so they can be watched with `status --raw`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RawState([u8; ControllerState::PAYLOAD_LEN]);
+
+impl RawState {
+    /// Status bits with a known meaning: calibration active, left and right done
+    pub const STATUS_KNOWN: u8 = 0x70;
+
+    /// Offsets of the bytes whose meaning is unknown
+    pub const UNKNOWN_BYTES: [usize; 5] = [5, 6, 7, 12, 13];
+
+    /// Offsets that start a new group in the [`Display`](fmt::Display) output
+    pub const GROUP_STARTS: [usize; 6] = [1, 5, 8, 9, 11, 12];
+
+    pub fn bytes(&self) -> &[u8; ControllerState::PAYLOAD_LEN] {
+        &self.0
+    }
+
+    pub fn status(&self) -> u8 {
+        self.0[8]
+    }
+
+    /// Status bits that are set but not decoded.
+    pub fn unknown_status_bits(&self) -> u8 {
+        self.0[8] & !Self::STATUS_KNOWN
+    }
+
+    /// Bits of the second button byte that are set but aren't buttons of `model`.
+    pub fn unknown_button_bits(&self, model: ControllerModel) -> u8 {
+        self.0[10] & !model.high_button_mask()
+    }
+
+    /// The unidentified bytes with their offsets.
+    pub fn unknown_bytes(&self) -> [(usize, u8); 5] {
+        Self::UNKNOWN_BYTES.map(|offset| (offset, self.0[offset]))
+    }
+
+    /// Overwrite one byte. Decoded bytes are replaced again by
+    /// [`ControllerState::to_payload`], so this is only useful for the unknown ones.
+    pub fn set_byte(&mut self, offset: usize, value: u8) {
+        self.0[offset] = value;
+    }
+
+    /// Offsets of the bytes that differ from `other`.
+    pub fn changed_from(&self, other: &RawState) -> Vec<usize> {
+        (0..self.0.len()).filter(|&i| self.0[i] != other.0[i]).collect()
+    }
+}
+
+impl Default for RawState {
+    fn default() -> Self {
+        let mut bytes = [0u8; ControllerState::PAYLOAD_LEN];
+        bytes[0] = ControllerSubCommand::GetState as u8;
+        Self(bytes)
+    }
+}
+
+/// Hex bytes, grouped as subcommand, sticks, unknown, status, buttons,
+/// battery and unknown.
+impl fmt::Display for RawState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if RawState::GROUP_STARTS.contains(&i) {
+                write!(f, " ")?;
+            }
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Decoded `GetState` reply.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerState {
+    pub left_x: u8,
+    pub left_y: u8,
+    pub right_x: u8,
+    pub right_y: u8,
+    pub buttons: Buttons,
+    pub battery: u8,
+    pub cal_active: bool,
+    pub cal_left: bool,
+    pub cal_right: bool,
+    pub model: ControllerModel,
+    /// The payload this state was parsed from. Its undecoded bytes are sent
+    /// back unchanged by [`ControllerState::to_payload`].
+    pub raw: RawState,
+}
+
+impl ControllerState {
+    /// Length of the `GetState` reply payload
+    pub const PAYLOAD_LEN: usize = 14;
+
+    pub fn parse(model: ControllerModel, payload: &[u8]) -> Option<Self> {
+        if payload.len() < Self::PAYLOAD_LEN || payload[0] != ControllerSubCommand::GetState as u8 {
+            return None;
+        }
+
+        let mut raw = RawState::default();
+        raw.0.copy_from_slice(&payload[..Self::PAYLOAD_LEN]);
+        let status = payload[8];
+        Some(Self {
+            left_x: payload[1],
+            left_y: payload[2],
+            right_x: payload[3],
+            right_y: payload[4],
+            buttons: model.decode_buttons(u16::from_le_bytes([payload[9], payload[10] & model.high_button_mask()])),
+            battery: payload[11],
+            cal_active: (status >> 4) & 1 != 0,
+            cal_left: (status >> 5) & 1 != 0,
+            cal_right: (status >> 6) & 1 != 0,
+            model,
+            raw,
+        })
+    }
+
+    /// Sticks centred and nothing pressed, for when the controller is away.
+    pub fn neutral(model: ControllerModel) -> Self {
+        Self {
+            left_x: Axis::CENTRE,
+            left_y: Axis::CENTRE,
+            right_x: Axis::CENTRE,
+            right_y: Axis::CENTRE,
+            model,
+            ..Self::default()
+        }
+    }
+
+    /// Encode the state as a `GetState` reply. Bytes and bits that aren't
+    /// decoded are taken from [`ControllerState::raw`].
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = self.raw.0.to_vec();
+        payload[0] = ControllerSubCommand::GetState as u8;
+        payload[1] = self.left_x;
+        payload[2] = self.left_y;
+        payload[3] = self.right_x;
+        payload[4] = self.right_y;
+
+        let mut status = self.raw.unknown_status_bits();
+        if self.cal_active { status |= 1 << 4; }
+        if self.cal_left { status |= 1 << 5; }
+        if self.cal_right { status |= 1 << 6; }
+        payload[8] = status;
+
+        let [low, high] = self.model.encode_buttons(self.buttons).to_le_bytes();
+        payload[9] = low;
+        payload[10] = self.raw.unknown_button_bits(self.model) | high;
+        payload[11] = self.battery;
+        payload
+    }
+
+    pub fn pressed(&self, button: Button) -> bool {
+        self.buttons.pressed(button)
+    }
+
+    /// Raw reading, 0 to 255 with 127 at centre.
+    pub fn axis(&self, axis: Axis) -> u8 {
+        match axis {
+            Axis::LeftX => self.left_x,
+            Axis::LeftY => self.left_y,
+            Axis::RightX => self.right_x,
+            Axis::RightY => self.right_y,
+        }
+    }
+
+    pub fn set_axis(&mut self, axis: Axis, value: u8) {
+        match axis {
+            Axis::LeftX => self.left_x = value,
+            Axis::LeftY => self.left_y = value,
+            Axis::RightX => self.right_x = value,
+            Axis::RightY => self.right_y = value,
+        }
+    }
+
+    /// Reading scaled to -1.0..=1.0, positive up and right.
+    pub fn axis_normalized(&self, axis: Axis) -> f32 {
+        Axis::normalize(self.axis(axis))
+    }
+}
+
+/// One status line: sticks, buttons and battery.
+impl fmt::Display for ControllerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mark = |button, symbol| if self.pressed(button) { symbol } else { " " };
+        write!(f, "LX: {:3} LY: {:3} RX: {:3} RY: {:3} | ", self.left_x, self.left_y, self.right_x, self.right_y)?;
+        match self.model {
+            ControllerModel::IqGen2 => write!(
+                f,
+                "L: {}{} R: {}{} E: {}{} F: {}{} | L3: {} R3: {}",
+                mark(Button::LUp, "^"), mark(Button::LDown, "v"),
+                mark(Button::RUp, "^"), mark(Button::RDown, "v"),
+                mark(Button::EUp, "^"), mark(Button::EDown, "v"),
+                mark(Button::FUp, "^"), mark(Button::FDown, "v"),
+                mark(Button::L3, "X"), mark(Button::R3, "X"),
+            )?,
+            ControllerModel::V5 => write!(
+                f,
+                "L: {}{} R: {}{} | {}{}{}{} | {}{}{}{}",
+                mark(Button::L1, "1"), mark(Button::L2, "2"),
+                mark(Button::R1, "1"), mark(Button::R2, "2"),
+                mark(Button::Up, "^"), mark(Button::Down, "v"),
+                mark(Button::Left, "<"), mark(Button::Right, ">"),
+                mark(Button::X, "X"), mark(Button::B, "B"),
+                mark(Button::Y, "Y"), mark(Button::A, "A"),
+            )?,
+        }
+        write!(f, " | Bat: {:3}%", self.battery)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A state with every decoded field away from its default
+    fn sample(model: ControllerModel) -> ControllerState {
+        ControllerState {
+            left_x: 0,
+            left_y: 64,
+            right_x: 200,
+            right_y: 255,
+            buttons: model.buttons().iter().copied().step_by(2).collect(),
+            battery: 87,
+            cal_active: true,
+            cal_left: false,
+            cal_right: true,
+            model,
+            raw: RawState::default(),
+        }
+    }
+
+    #[test]
+    fn payload_round_trip() {
+        for model in [ControllerModel::IqGen2, ControllerModel::V5] {
+            let state = sample(model);
+            let payload = state.to_payload();
+            let parsed = ControllerState::parse(model, &payload).unwrap();
+
+            assert_eq!(ControllerState { raw: state.raw, ..parsed }, state, "{}", model);
+            assert_eq!(&parsed.raw.bytes()[..], &payload[..]);
+            assert_eq!(parsed.to_payload(), payload);
+        }
+    }
+
+    #[test]
+    fn unknown_bytes_and_bits_survive_a_round_trip() {
+        for model in [ControllerModel::IqGen2, ControllerModel::V5] {
+            let mut payload = sample(model).to_payload();
+            for offset in RawState::UNKNOWN_BYTES {
+                payload[offset] = 0xA0 | offset as u8;
+            }
+            payload[8] |= !RawState::STATUS_KNOWN;
+            payload[10] |= !model.high_button_mask();
+
+            let mut state = ControllerState::parse(model, &payload).unwrap();
+            assert_eq!(state.raw.unknown_bytes(), RawState::UNKNOWN_BYTES.map(|offset| (offset, 0xA0 | offset as u8)));
+            assert_eq!(state.raw.unknown_status_bits(), !RawState::STATUS_KNOWN);
+            assert_eq!(state.raw.unknown_button_bits(model), !model.high_button_mask());
+            // The unknown high bits aren't read as buttons
+            assert_eq!(state.buttons, sample(model).buttons, "{}", model);
+
+            // Changing what is decoded leaves them alone
+            state.left_x = 99;
+            state.buttons = Buttons::NONE;
+            state.cal_active = false;
+            let encoded = state.to_payload();
+            for offset in RawState::UNKNOWN_BYTES {
+                assert_eq!(encoded[offset], payload[offset], "byte {}", offset);
+            }
+            assert_eq!(encoded[8] & !RawState::STATUS_KNOWN, !RawState::STATUS_KNOWN);
+            assert_eq!(encoded[10], !model.high_button_mask(), "{}", model);
+        }
+    }
+
+    #[test]
+    fn parse_rejects_short_and_foreign_payloads() {
+        let payload = sample(ControllerModel::IqGen2).to_payload();
+        assert_eq!(ControllerState::parse(ControllerModel::IqGen2, &payload[..13]), None);
+
+        let mut other = payload.clone();
+        other[0] = ControllerSubCommand::DevState as u8;
+        assert_eq!(ControllerState::parse(ControllerModel::IqGen2, &other), None);
+    }
+
+    #[test]
+    fn normalize_reaches_both_ends() {
+        assert_eq!(Axis::normalize(0), -1.0);
+        assert_eq!(Axis::normalize(Axis::CENTRE), 0.0);
+        assert_eq!(Axis::normalize(255), 1.0);
+
+        assert_eq!(Axis::denormalize(-1.0), 0);
+        assert_eq!(Axis::denormalize(0.0), Axis::CENTRE);
+        assert_eq!(Axis::denormalize(1.0), 255);
+        assert_eq!(Axis::denormalize(-3.0), 0);
+        assert_eq!(Axis::denormalize(3.0), 255);
+
+        for raw in [0, 1, 63, 126, 127, 128, 200, 254, 255] {
+            assert_eq!(Axis::denormalize(Axis::normalize(raw)), raw);
+        }
+    }
+
+    #[test]
+    fn buttons_iterate_in_all_order() {
+        let buttons: Buttons = [Button::A, Button::FUp, Button::LUp].into_iter().collect();
+
+        assert_eq!(buttons.iter().collect::<Vec<_>>(), [Button::LUp, Button::FUp, Button::A]);
+        assert_eq!(buttons.into_iter().collect::<Buttons>(), buttons);
+        assert!(buttons.pressed(Button::FUp) && !buttons.pressed(Button::FDown));
+        assert_eq!(Buttons::NONE.iter().next(), None);
+        assert_eq!(Button::ALL.into_iter().collect::<Buttons>().iter().count(), Button::ALL.len());
+    }
+}
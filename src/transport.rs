@@ -1,7 +1,18 @@
-use anyhow::Result;
+use async_trait::async_trait;
+use std::io;
 
 pub trait Transport: Send {
-    fn send_bytes(&mut self, data: &[u8]) -> Result<()>;
-    fn receive_bytes(&mut self, buffer: &mut [u8]) -> Result<usize>;
-    fn clear_buffer(&mut self) -> Result<()>;
+    fn send_bytes(&mut self, data: &[u8]) -> io::Result<()>;
+    fn receive_bytes(&mut self, buffer: &mut [u8]) -> io::Result<usize>;
+    fn clear_buffer(&mut self) -> io::Result<()>;
+}
+
+/// Non-blocking counterpart of [`Transport`] for use on a tokio runtime.
+#[async_trait]
+pub trait AsyncTransport: Send {
+    async fn send_bytes(&mut self, data: &[u8]) -> io::Result<()>;
+    /// Wait until at least one byte is available. `Ok(0)` means nothing is
+    /// buffered right now and the caller should try again later.
+    async fn receive_bytes(&mut self, buffer: &mut [u8]) -> io::Result<usize>;
+    fn clear_buffer(&mut self) -> io::Result<()>;
 }
@@ -0,0 +1,93 @@
+use vex_controller::config::Config;
+use vex_controller::daemon::{assign_players, DaemonTarget};
+use vex_controller::model::{ControllerModel, DeviceId};
+use vex_controller::protocol::Axis;
+use vex_controller::serial::PortSelector;
+
+#[test]
+fn player_zero_is_rejected() {
+    let error = Config::parse("[[device]]\nserial = \"A\"\nplayer = 0").unwrap_err();
+    assert!(error.to_string().contains("player 0"), "{}", error);
+}
+
+#[test]
+fn controllers_sharing_a_player_are_rejected() {
+    let text = "[[device]]\nserial = \"A\"\nplayer = 2\n\n[[device]]\nserial = \"B\"\nplayer = 2";
+    let error = Config::parse(text).unwrap_err();
+    assert!(error.to_string().contains("both have player 2"), "{}", error);
+}
+
+#[test]
+fn entries_for_one_controller_may_share_a_player() {
+    let text = "[[device]]\nserial = \"A\"\npair_id = 1\nplayer = 2\n\n[[device]]\nserial = \"A\"\npair_id = 3\nplayer = 2";
+    assert_eq!(Config::parse(text).unwrap().devices.len(), 2);
+}
+
+fn player_for(config: &Config, serial: &str, pair_id: u8) -> Option<u8> {
+    let id = DeviceId { serial_number: serial.to_string(), pair_id };
+    config.device(&id).map(|device| device.player.unwrap_or_default())
+}
+
+#[test]
+fn entries_with_a_matching_pair_id_win() {
+    // The entry without a pair ID comes first, so order alone wouldn't pick the other
+    let text = "[[device]]\nserial = \"A\"\nplayer = 1\n\n[[device]]\nserial = \"A\"\npair_id = 3\nplayer = 1\nmapping = { e_up = \"e_down\" }";
+    let config = Config::parse(text).unwrap();
+
+    let pair_id_of_entry = |pair_id| config.device(&DeviceId { serial_number: "A".into(), pair_id }).unwrap().pair_id;
+    assert_eq!(pair_id_of_entry(3), Some(3));
+    assert_eq!(pair_id_of_entry(4), None);
+    assert_eq!(player_for(&config, "B", 3), None);
+    assert!(config.device_without_pair_id("A").unwrap().mapping.is_empty());
+}
+
+#[test]
+fn entries_for_another_pair_id_are_skipped() {
+    let config = Config::parse("[[device]]\nserial = \"A\"\npair_id = 3\nplayer = 2").unwrap();
+
+    assert_eq!(player_for(&config, "A", 3), Some(2));
+    assert_eq!(player_for(&config, "A", 4), None);
+    assert!(config.device_without_pair_id("A").is_none());
+}
+
+#[test]
+fn calibration_moves_the_centre_and_keeps_the_ends() {
+    let text = "[[device]]\nserial = \"A\"\ncalibration = { centre = { left_x = 131, right_y = 120 } }";
+    let calibration = &Config::parse(text).unwrap().devices[0].calibration;
+
+    for (axis, centre) in [(Axis::LeftX, 131), (Axis::RightY, 120), (Axis::LeftY, Axis::CENTRE)] {
+        assert_eq!(calibration.correct(axis, 0), 0, "{:?}", axis);
+        assert_eq!(calibration.correct(axis, centre), Axis::CENTRE, "{:?}", axis);
+        assert_eq!(calibration.correct(axis, 255), 255, "{:?}", axis);
+    }
+    // Readings between the ends stay in order
+    let corrected: Vec<u8> = (0..=255).map(|raw| calibration.correct(Axis::LeftX, raw)).collect();
+    assert!(corrected.windows(2).all(|pair| pair[0] <= pair[1]));
+}
+
+#[test]
+fn calibration_deadzone_centres_small_readings() {
+    let text = "[[device]]\nserial = \"A\"\ncalibration = { deadzone = 6, centre = { left_x = 131 } }";
+    let calibration = &Config::parse(text).unwrap().devices[0].calibration;
+
+    assert_eq!(calibration.correct(Axis::LeftY, 121), Axis::CENTRE);
+    assert_eq!(calibration.correct(Axis::LeftY, 133), Axis::CENTRE);
+    assert_eq!(calibration.correct(Axis::LeftY, 120), 120);
+    assert_eq!(calibration.correct(Axis::LeftY, 134), 134);
+    // The deadzone applies around the corrected centre
+    assert_eq!(calibration.correct(Axis::LeftX, 136), Axis::CENTRE);
+    assert_eq!(calibration.correct(Axis::LeftX, 0), 0);
+    assert_eq!(calibration.correct(Axis::LeftX, 255), 255);
+}
+
+fn target(player: Option<u8>) -> DaemonTarget {
+    DaemonTarget { selector: PortSelector::Auto, model: ControllerModel::IqGen2, settings: None, player }
+}
+
+#[test]
+fn unconfigured_players_get_the_lowest_free_numbers() {
+    let mut targets = [target(None), target(Some(1)), target(None), target(Some(3))];
+    assign_players(&mut targets);
+    let players: Vec<_> = targets.iter().map(|target| target.player).collect();
+    assert_eq!(players, [Some(2), Some(1), Some(4), Some(3)]);
+}
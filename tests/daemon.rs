@@ -0,0 +1,192 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use vex_controller::async_controller::AsyncVexController;
+use vex_controller::daemon::{Daemon, DaemonEvent, StateSink};
+use vex_controller::model::ControllerModel;
+use vex_controller::protocol::{Command, ControllerState, ControllerSubCommand, VexControllerBuilder};
+use vex_controller::simulator::SimulatedController;
+
+/// Records everything it is sent, taking `delay` over each state.
+#[derive(Clone)]
+struct CollectingSink {
+    events: Arc<Mutex<Vec<DaemonEvent>>>,
+    delay: Duration,
+}
+
+impl CollectingSink {
+    fn new(delay: Duration) -> Self {
+        Self { events: Arc::new(Mutex::new(Vec::new())), delay }
+    }
+
+    fn events(&self) -> Vec<DaemonEvent> {
+        self.events.lock().unwrap().clone()
+    }
+
+    fn states(&self) -> Vec<ControllerState> {
+        self.events()
+            .into_iter()
+            .filter_map(|event| match event {
+                DaemonEvent::State(state) => Some(state),
+                DaemonEvent::Disconnected => None,
+            })
+            .collect()
+    }
+}
+
+#[async_trait]
+impl StateSink for CollectingSink {
+    fn name(&self) -> &str {
+        "collecting"
+    }
+
+    async fn handle(&mut self, state: &ControllerState) -> Result<()> {
+        tokio::time::sleep(self.delay).await;
+        self.events.lock().unwrap().push(DaemonEvent::State(*state));
+        Ok(())
+    }
+
+    async fn disconnected(&mut self) -> Result<()> {
+        self.events.lock().unwrap().push(DaemonEvent::Disconnected);
+        Ok(())
+    }
+}
+
+/// Connects to each simulator in turn, then fails as if the controller was unplugged.
+fn connect_to(sims: Vec<SimulatedController>, builder: VexControllerBuilder) -> impl FnMut() -> Result<AsyncVexController> {
+    let mut sims = sims.into_iter();
+    move || match sims.next() {
+        Some(sim) => Ok(builder.clone().build_async(Box::new(sim))),
+        None => Err(anyhow!("No controller")),
+    }
+}
+
+/// Wait up to five seconds for `done` to hold.
+async fn wait_until(mut done: impl FnMut() -> bool) {
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+    while !done() {
+        assert!(tokio::time::Instant::now() < deadline, "timed out waiting for the daemon");
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+}
+
+#[tokio::test]
+async fn states_reach_every_sink_without_waiting_for_a_slow_one() {
+    let sim = SimulatedController::new();
+    sim.handle().set_battery(55);
+
+    let mut daemon = Daemon::new();
+    let first = CollectingSink::new(Duration::ZERO);
+    let second = CollectingSink::new(Duration::ZERO);
+    let slow = CollectingSink::new(Duration::from_secs(1));
+    daemon.add_sink(first.clone());
+    daemon.add_sink(second.clone());
+    daemon.add_sink(slow.clone());
+    let task = tokio::spawn(daemon.run(connect_to(vec![sim], VexControllerBuilder::new())));
+
+    // More states than a sink can queue, so the slow one has to fall behind
+    wait_until(|| first.states().len() >= 25 && second.states().len() >= 25).await;
+    task.abort();
+
+    assert!(first.states().iter().chain(&second.states()).all(|state| state.battery == 55));
+    assert!(slow.states().len() <= 1, "the slow sink kept up with {} states", slow.states().len());
+}
+
+/// A short reply timeout, so runs of lost replies go by quickly
+fn quick_link() -> VexControllerBuilder {
+    VexControllerBuilder::new().timeout(Duration::from_millis(20))
+}
+
+/// Wait for a state showing `battery`, set after any faults queued before it.
+async fn wait_for_battery(sink: &CollectingSink, battery: u8) {
+    wait_until(|| sink.states().iter().any(|state| state.battery == battery)).await;
+}
+
+#[tokio::test]
+async fn a_run_of_timeouts_reconnects() {
+    let first = SimulatedController::new();
+    let second = SimulatedController::new();
+    let handle = first.handle();
+    second.handle().set_battery(42);
+
+    let mut daemon = Daemon::new();
+    let sink = CollectingSink::new(Duration::ZERO);
+    daemon.add_sink(sink.clone());
+    let task = tokio::spawn(daemon.run(connect_to(vec![first, second], quick_link())));
+    wait_until(|| !sink.states().is_empty()).await;
+
+    // One more than it takes, in case the periodic device state read gets one
+    handle.drop_replies(6);
+    wait_for_battery(&sink, 42).await;
+    task.abort();
+
+    let events = sink.events();
+    let gone = events.iter().position(|event| *event == DaemonEvent::Disconnected).expect("no Disconnected event");
+    assert!(events[..gone].iter().all(|event| matches!(event, DaemonEvent::State(state) if state.battery == 100)));
+    assert!(events[gone + 1..].iter().all(|event| matches!(event, DaemonEvent::State(state) if state.battery == 42)));
+}
+
+#[tokio::test]
+async fn timeouts_broken_up_by_a_reply_keep_the_connection() {
+    let sim = SimulatedController::new();
+    let handle = sim.handle();
+
+    let mut daemon = Daemon::new();
+    let sink = CollectingSink::new(Duration::ZERO);
+    daemon.add_sink(sink.clone());
+    // Reconnecting would find no controller and never publish another state
+    let task = tokio::spawn(daemon.run(connect_to(vec![sim], quick_link())));
+    wait_until(|| !sink.states().is_empty()).await;
+
+    // A reply that can't be parsed isn't a timeout, so it ends the run
+    handle.drop_replies(4);
+    handle.replace_reply(Command::ControllerCdc as u8, &[ControllerSubCommand::GetState as u8]);
+    handle.drop_replies(4);
+    handle.set_battery(50);
+    wait_for_battery(&sink, 50).await;
+
+    // And so does a good state
+    handle.drop_replies(4);
+    handle.set_battery(51);
+    wait_for_battery(&sink, 51).await;
+    task.abort();
+
+    assert!(!sink.events().contains(&DaemonEvent::Disconnected));
+}
+
+#[tokio::test]
+async fn every_sink_hears_of_the_disconnect_then_gets_the_next_controller() {
+    let first = SimulatedController::new();
+    let second = SimulatedController::with_model(ControllerModel::V5);
+    let handle = first.handle();
+    second.handle().set_battery(42);
+
+    let mut daemon = Daemon::new();
+    let sinks = [CollectingSink::new(Duration::ZERO), CollectingSink::new(Duration::ZERO)];
+    for sink in &sinks {
+        daemon.add_sink(sink.clone());
+    }
+    // The model comes from whatever is found on each connect, as the daemon does with ports
+    let mut controllers = vec![first, second].into_iter();
+    let connect = move || match controllers.next() {
+        Some(sim) => {
+            let model = sim.handle().state().model;
+            Ok(quick_link().model(model).build_async(Box::new(sim)))
+        }
+        None => Err(anyhow!("No controller")),
+    };
+    let task = tokio::spawn(daemon.run(connect));
+    wait_until(|| sinks.iter().all(|sink| !sink.states().is_empty())).await;
+
+    handle.drop_replies(6);
+    wait_until(|| sinks.iter().all(|sink| sink.states().iter().any(|state| state.battery == 42))).await;
+    task.abort();
+
+    for sink in &sinks {
+        let events = sink.events();
+        let gone = events.iter().position(|event| *event == DaemonEvent::Disconnected).expect("no Disconnected event");
+        assert!(events[..gone].iter().all(|event| matches!(event, DaemonEvent::State(state) if state.model == ControllerModel::IqGen2)));
+        assert!(events[gone + 1..].iter().all(|event| matches!(event, DaemonEvent::State(state) if state.model == ControllerModel::V5 && state.battery == 42)));
+    }
+}
@@ -0,0 +1,444 @@
+use std::ops::ControlFlow;
+use std::time::{Duration, Instant};
+use vex_controller::async_controller::AsyncVexController;
+use vex_controller::calibration::{run_calibration, CalibrationOutcome};
+use vex_controller::error::{ErrorKind, ProtocolError, NACK_NO_TRANSFER, NACK_PROGRAM_CRC};
+use vex_controller::event::ControllerEvent;
+use vex_controller::firmware::{self, FirmwareUpload, UpdateOptions};
+use vex_controller::model::ControllerModel;
+use vex_controller::protocol::{
+    Button, ChargeState, Command, ControllerSubCommand, DeviceState, ProductType, RadioLink, SystemStatus, TestData, Version, VexController,
+    VexControllerBuilder, FACTORY_PAIR_ID,
+};
+use vex_controller::simulator::{Scenario, SimulatedController, SimulatorHandle};
+
+fn connect() -> (VexController, SimulatorHandle) {
+    let sim = SimulatedController::new();
+    let handle = sim.handle();
+    (VexController::new(Box::new(sim)), handle)
+}
+
+#[test]
+fn get_state_reads_scripted_inputs() {
+    let (mut controller, handle) = connect();
+    handle.load_scenario(&Scenario::parse("0 left_x=255 right_y=0 e_up=1 r3=1 battery=42").unwrap());
+
+    let state = controller.get_state().unwrap();
+    assert_eq!((state.left_x, state.left_y, state.right_x, state.right_y), (255, 127, 127, 0));
+    assert!(state.pressed(Button::EUp));
+    assert!(state.pressed(Button::R3));
+    assert!(!state.pressed(Button::EDown));
+    assert_eq!(state.battery, 42);
+}
+
+#[test]
+fn get_state_follows_the_script_clock() {
+    let (mut controller, handle) = connect();
+    handle.at(Duration::from_millis(50), |state| state.left_y = 0);
+
+    assert_eq!(controller.get_state().unwrap().left_y, 127);
+    std::thread::sleep(Duration::from_millis(60));
+    assert_eq!(controller.get_state().unwrap().left_y, 0);
+    assert_eq!(handle.pending_steps(), 0);
+}
+
+#[test]
+fn events_report_changes_after_the_baseline() {
+    let (mut controller, handle) = connect();
+    handle.load_scenario(&Scenario::parse("30 f_up=1 battery=80\n60 f_up=0").unwrap());
+
+    let events: Vec<_> = controller.events(Duration::from_millis(10)).take(3).map(Result::unwrap).collect();
+    assert_eq!(
+        events,
+        [ControllerEvent::ButtonPressed(Button::FUp), ControllerEvent::BatteryChanged(80), ControllerEvent::ButtonReleased(Button::FUp)]
+    );
+}
+
+#[test]
+fn get_versions_parses_dotted_versions_from_the_text() {
+    let (mut controller, handle) = connect();
+    handle.set_versions("VEX IQ2 Controller v1.2.3, radio 1.0.2b4");
+
+    let versions = controller.get_versions().unwrap();
+    assert_eq!(versions.text, "VEX IQ2 Controller v1.2.3, radio 1.0.2b4");
+    assert_eq!(
+        versions.versions,
+        [Version { major: 1, minor: 2, build: 3, beta: 0 }, Version { major: 1, minor: 0, build: 2, beta: 4 }]
+    );
+    assert_eq!(versions.main(), Some(Version { major: 1, minor: 2, build: 3, beta: 0 }));
+}
+
+#[test]
+fn get_versions_keeps_text_without_versions() {
+    let (mut controller, handle) = connect();
+    handle.set_versions("VEX IQ2 Controller (simulated)");
+
+    let versions = controller.get_versions().unwrap();
+    assert_eq!(versions.text, "VEX IQ2 Controller (simulated)");
+    assert!(versions.versions.is_empty());
+    assert_eq!(versions.main(), None);
+}
+
+#[test]
+fn pair_id_round_trip() {
+    let (mut controller, handle) = connect();
+    handle.set_pair_id(7);
+    assert_eq!(controller.get_pair_id().unwrap(), 7);
+
+    controller.set_pair_id(3).unwrap();
+    assert_eq!(controller.get_pair_id().unwrap(), 3);
+}
+
+#[test]
+fn pair_id_resets_to_the_factory_value() {
+    let (mut controller, handle) = connect();
+    handle.set_pair_id(9);
+
+    controller.set_pair_id(FACTORY_PAIR_ID).unwrap();
+    assert_eq!(controller.get_pair_id().unwrap(), FACTORY_PAIR_ID);
+}
+
+#[test]
+fn system_status_round_trip() {
+    let (mut controller, handle) = connect();
+    let status = SystemStatus {
+        product_type: ProductType::Unknown(0x42),
+        system_version: Version { major: 1, minor: 2, build: 3, beta: 0 },
+        bootloader_version: Version { major: 0, minor: 9, build: 1, beta: 4 },
+        flags: 0x8001_0204,
+        extra: vec![0xDE, 0xAD],
+    };
+    handle.set_system_status(status.clone());
+
+    assert_eq!(controller.get_system_status().unwrap(), status);
+}
+
+#[test]
+fn device_state_round_trip() {
+    let (mut controller, handle) = connect();
+    let state = DeviceState { link: RadioLink::Connected, tethered: true, charging: ChargeState::Unknown(7) };
+    handle.set_device_state(state);
+
+    assert_eq!(controller.get_device_state().unwrap(), state);
+}
+
+#[test]
+fn short_device_state_is_a_parse_error() {
+    let (mut controller, handle) = connect();
+    handle.inject_response(Command::ControllerCdc as u8, &[ControllerSubCommand::DevState as u8, 2]);
+
+    assert!(matches!(controller.get_device_state(), Err(ProtocolError::Parse(_))));
+    // The full reply that followed is skipped as stale by the next request
+    assert_eq!(controller.get_device_state().unwrap(), DeviceState::default());
+}
+
+#[test]
+fn test_data_follows_the_simulated_inputs() {
+    let (mut controller, handle) = connect();
+    handle.load_scenario(&Scenario::parse("0 left_x=255 right_y=0 f_up=1").unwrap());
+    controller.set_test_mode(true).unwrap();
+
+    let data = controller.get_test_data().unwrap();
+    // The simulator scales the 0-255 stick values to the 12-bit range, so 127 reads 2039
+    assert_eq!(data.sticks, [TestData::ADC_MAX, 2039, 2039, 0]);
+    assert!(data.buttons.pressed(Button::FUp));
+    assert_eq!(data.buttons.iter().count(), 1);
+    assert_eq!(data.model, ControllerModel::IqGen2);
+}
+
+#[test]
+fn test_data_sticks_are_little_endian() {
+    let (mut controller, handle) = connect();
+    controller.set_test_mode(true).unwrap();
+    handle.inject_response(
+        Command::ControllerCdc as u8,
+        &[ControllerSubCommand::GetTestData as u8, 0xFF, 0x0F, 0x00, 0x08, 0x01, 0x00, 0x34, 0x12, 0x00, 0x00],
+    );
+
+    let data = controller.get_test_data().unwrap();
+    assert_eq!(data.sticks, [0x0FFF, 0x0800, 0x0001, 0x1234]);
+    assert!(data.buttons.is_empty());
+}
+
+fn connect_without_retries() -> (VexController, SimulatorHandle) {
+    let sim = SimulatedController::new();
+    let handle = sim.handle();
+    (VexControllerBuilder::new().timeout(Duration::from_millis(50)).retries(0).build(Box::new(sim)), handle)
+}
+
+fn stale_state_reply(handle: &SimulatorHandle) {
+    handle.inject_response(Command::ControllerCdc as u8, &[ControllerSubCommand::GetState as u8, 0, 0, 0]);
+}
+
+#[test]
+fn stale_replies_are_skipped() {
+    let (mut controller, handle) = connect();
+    handle.set_versions("VEX IQ2 Controller v1.2.3");
+    stale_state_reply(&handle);
+
+    assert_eq!(controller.get_versions().unwrap().text, "VEX IQ2 Controller v1.2.3");
+    let stats = controller.stats();
+    assert_eq!((stats.mismatches, stats.timeouts), (1, 0));
+}
+
+#[test]
+fn only_stale_replies_are_a_mismatch() {
+    let (mut controller, handle) = connect_without_retries();
+    handle.drop_replies(1);
+    stale_state_reply(&handle);
+
+    match controller.get_versions() {
+        Err(ProtocolError::Mismatch(mismatch)) => {
+            assert_eq!(mismatch.expected, (Command::ControllerCdc as u8, ControllerSubCommand::GetVersions as u8));
+            assert_eq!(mismatch.received, (Command::ControllerCdc as u8, Some(ControllerSubCommand::GetState as u8)));
+        }
+        other => panic!("expected a mismatch, got {:?}", other),
+    }
+    assert_eq!(controller.stats().mismatches, 1);
+}
+
+#[test]
+fn no_reply_at_all_is_a_timeout() {
+    let (mut controller, handle) = connect_without_retries();
+    handle.drop_replies(1);
+
+    assert!(matches!(controller.get_versions(), Err(ProtocolError::Timeout)));
+    let stats = controller.stats();
+    assert_eq!((stats.mismatches, stats.timeouts), (0, 1));
+}
+
+#[test]
+fn lost_replies_are_retried_until_the_budget_runs_out() {
+    let sim = SimulatedController::new();
+    let handle = sim.handle();
+    let mut controller =
+        VexControllerBuilder::new().timeout(Duration::from_millis(30)).retries(2).backoff(Duration::from_millis(1)).build(Box::new(sim));
+
+    // Two lost replies are covered by the two retries
+    handle.drop_replies(2);
+    assert_eq!(controller.get_pair_id().unwrap(), FACTORY_PAIR_ID);
+    assert_eq!(controller.stats().timeouts, 2);
+
+    // A third one isn't
+    handle.drop_replies(3);
+    assert!(matches!(controller.get_pair_id(), Err(ProtocolError::Timeout)));
+    assert_eq!(controller.stats().timeouts, 5);
+    assert_eq!(controller.get_pair_id().unwrap(), FACTORY_PAIR_ID);
+}
+
+#[test]
+fn transfer_timeout_only_applies_to_file_transfers() {
+    let sim = SimulatedController::new();
+    let handle = sim.handle();
+    let mut controller = VexControllerBuilder::new()
+        .timeout(Duration::from_millis(30))
+        .transfer_timeout(Duration::from_millis(300))
+        .build(Box::new(sim));
+
+    handle.drop_replies(1);
+    let start = Instant::now();
+    assert!(controller.get_system_status().is_err());
+    assert!(start.elapsed() < Duration::from_millis(200), "{:?}", start.elapsed());
+
+    handle.drop_replies(1);
+    let start = Instant::now();
+    let error = firmware::abort_transfer(&mut controller).unwrap_err();
+    assert!(matches!(protocol_error(&error), Some(ProtocolError::Timeout)), "{:#}", error);
+    assert!(start.elapsed() >= Duration::from_millis(300), "{:?}", start.elapsed());
+}
+
+#[test]
+fn calibration_completes_once_both_sticks_are_done() {
+    let (mut controller, handle) = connect();
+    handle.load_scenario(&Scenario::parse("50 cal_left=1\n100 cal_right=1\n150 cal_active=0").unwrap());
+
+    let mut seen_left_only = false;
+    let outcome = run_calibration(&mut controller, |state| {
+        seen_left_only |= state.cal_left && !state.cal_right;
+    })
+    .unwrap();
+
+    assert_eq!(outcome, CalibrationOutcome::Complete);
+    assert!(seen_left_only);
+}
+
+#[test]
+fn calibration_ending_early_is_aborted() {
+    let (mut controller, handle) = connect();
+    handle.load_scenario(&Scenario::parse("50 cal_left=1\n100 cal_active=0").unwrap());
+
+    assert_eq!(run_calibration(&mut controller, |_| {}).unwrap(), CalibrationOutcome::Aborted);
+}
+
+#[test]
+fn abort_calibration_clears_the_calibration_bits() {
+    let (mut controller, handle) = connect();
+    controller.start_calibration().unwrap();
+    handle.update(|state| state.cal_left = true);
+    assert!(controller.get_state().unwrap().cal_active);
+
+    controller.abort_calibration().unwrap();
+    let state = controller.get_state().unwrap();
+    assert!(!state.cal_active && !state.cal_left && !state.cal_right);
+}
+
+#[test]
+fn rumble_and_screen_text_need_experimental_commands() {
+    let sim = SimulatedController::with_model(ControllerModel::V5);
+    let handle = sim.handle();
+    let mut controller = VexController::new(Box::new(sim));
+
+    assert_eq!(controller.rumble("-.").unwrap_err().kind(), ErrorKind::Experimental);
+    assert_eq!(controller.set_screen_line(0, "Hello").unwrap_err().kind(), ErrorKind::Experimental);
+    assert!(handle.rumbles().is_empty());
+    assert_eq!(handle.screen()[0], "");
+}
+
+#[test]
+fn experimental_commands_reach_the_controller_once_enabled() {
+    let sim = SimulatedController::with_model(ControllerModel::V5);
+    let handle = sim.handle();
+    let mut controller = VexControllerBuilder::new().model(ControllerModel::V5).experimental(true).build(Box::new(sim));
+
+    controller.rumble("-.").unwrap();
+    controller.set_screen_line(0, "Hello").unwrap();
+    assert_eq!(handle.rumbles(), ["-."]);
+    assert_eq!(handle.screen()[0], "Hello");
+}
+
+fn connect_experimental() -> (VexController, SimulatorHandle) {
+    let sim = SimulatedController::new();
+    let handle = sim.handle();
+    (VexControllerBuilder::new().experimental(true).build(Box::new(sim)), handle)
+}
+
+fn firmware_image() -> Vec<u8> {
+    (0..5000u32).map(|i| (i * 7 % 251) as u8).collect()
+}
+
+fn firmware_options() -> UpdateOptions {
+    UpdateOptions { reboot: false, ..Default::default() }
+}
+
+fn protocol_error(error: &anyhow::Error) -> Option<&ProtocolError> {
+    error.chain().find_map(|e| e.downcast_ref::<ProtocolError>())
+}
+
+#[test]
+fn firmware_upload_needs_experimental_commands() {
+    let (mut controller, handle) = connect();
+    let error = FirmwareUpload::begin(&mut controller, &firmware_image(), &firmware_options()).err().unwrap();
+    assert_eq!(protocol_error(&error).map(ProtocolError::kind), Some(ErrorKind::Experimental));
+    assert_eq!(handle.firmware(), None);
+}
+
+#[test]
+fn firmware_upload_stores_the_image() {
+    let (mut controller, handle) = connect_experimental();
+    let image = firmware_image();
+
+    let mut written = Vec::new();
+    firmware::upload_firmware(&mut controller, &image, &firmware_options(), |progress| {
+        written.push(progress.written);
+        ControlFlow::Continue(())
+    })
+    .unwrap();
+
+    assert_eq!(written.last(), Some(&image.len()));
+    assert!(written.len() > 1, "expected several chunks, got {:?}", written);
+    assert_eq!(handle.firmware(), Some(image));
+}
+
+#[test]
+fn firmware_with_a_bad_crc_is_rejected() {
+    let (mut controller, handle) = connect_experimental();
+    // Resuming a transfer that was never started leaves the first bytes unwritten
+    let options = UpdateOptions { resume_from: 1024, ..firmware_options() };
+
+    let error = firmware::upload_firmware(&mut controller, &firmware_image(), &options, |_| ControlFlow::Continue(())).unwrap_err();
+    assert!(
+        matches!(protocol_error(&error), Some(ProtocolError::Nack { command: Command::FileExit, code: NACK_PROGRAM_CRC })),
+        "{:#}",
+        error
+    );
+    assert_eq!(handle.firmware(), None);
+}
+
+#[test]
+fn firmware_upload_resumes_where_it_stopped() {
+    let (mut controller, handle) = connect_experimental();
+    let image = firmware_image();
+
+    let offset = {
+        let mut upload = FirmwareUpload::begin(&mut controller, &image, &firmware_options()).unwrap();
+        upload.write_next_chunk().unwrap()
+    };
+    assert!(offset < image.len());
+
+    let options = UpdateOptions { resume_from: offset, ..firmware_options() };
+    let mut first = None;
+    firmware::upload_firmware(&mut controller, &image, &options, |progress| {
+        first.get_or_insert(progress.written);
+        ControlFlow::Continue(())
+    })
+    .unwrap();
+
+    assert!(first.unwrap() > offset);
+    assert_eq!(handle.firmware(), Some(image));
+}
+
+#[test]
+fn aborting_discards_the_open_transfer() {
+    let (mut controller, handle) = connect_experimental();
+    let image = firmware_image();
+
+    let mut upload = FirmwareUpload::begin(&mut controller, &image, &firmware_options()).unwrap();
+    upload.write_next_chunk().unwrap();
+    upload.abort().unwrap();
+
+    // Nothing is left open to abort or finish
+    let error = firmware::abort_transfer(&mut controller).unwrap_err();
+    assert!(matches!(protocol_error(&error), Some(ProtocolError::Nack { code: NACK_NO_TRANSFER, .. })), "{:#}", error);
+    assert_eq!(handle.firmware(), None);
+}
+
+fn connect_async(builder: VexControllerBuilder) -> (AsyncVexController, SimulatorHandle) {
+    let sim = SimulatedController::new();
+    let handle = sim.handle();
+    (builder.build_async(Box::new(sim)), handle)
+}
+
+#[tokio::test]
+async fn async_get_state_reads_scripted_inputs() {
+    let (mut controller, handle) = connect_async(VexControllerBuilder::new());
+    handle.load_scenario(&Scenario::parse("0 left_y=0 e_down=1 battery=64").unwrap());
+
+    let state = controller.get_state().await.unwrap();
+    assert_eq!((state.left_x, state.left_y), (127, 0));
+    assert!(state.pressed(Button::EDown));
+    assert_eq!(state.battery, 64);
+}
+
+#[tokio::test]
+async fn async_lost_reply_times_out() {
+    let (mut controller, handle) = connect_async(VexControllerBuilder::new().timeout(Duration::from_millis(30)));
+    handle.drop_replies(1);
+
+    let start = Instant::now();
+    assert!(matches!(controller.get_state().await, Err(ProtocolError::Timeout)));
+    assert!(start.elapsed() >= Duration::from_millis(30));
+    assert_eq!(controller.stats().timeouts, 1);
+
+    // The next command is answered as usual
+    assert_eq!(controller.get_state().await.unwrap().battery, 100);
+}
+
+#[tokio::test]
+async fn async_lost_reply_is_retried() {
+    let builder = VexControllerBuilder::new().timeout(Duration::from_millis(30)).retries(1).backoff(Duration::from_millis(1));
+    let (mut controller, handle) = connect_async(builder);
+    handle.drop_replies(1);
+
+    assert_eq!(controller.get_state().await.unwrap().battery, 100);
+    assert_eq!(controller.stats().timeouts, 1);
+}